@@ -1,11 +1,12 @@
 use log::{debug, warn};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Debug,
-    io::stdin,
+    io::{self, stdin},
+    ops::Range,
     path::PathBuf,
-    sync::{Mutex, MutexGuard, PoisonError},
+    str::FromStr,
     thread::sleep,
     time::Duration,
 };
@@ -13,8 +14,11 @@ use thiserror::Error;
 use ureq::http::StatusCode;
 
 use crate::{
+    AGENT, CONFIG, DuplicateClozes,
+    cache::Cache,
     handle_md::{ClozeData, Picture},
-    AGENT, CONFIG,
+    media,
+    sync_state::SyncState,
 };
 
 // Handles interaction with AnkiConnect.
@@ -22,11 +26,10 @@ use crate::{
 // and model <-> fields could be linked, but we dont really need it here and
 // it would complicate the serialization
 
-const MAX_BACKOFF: u8 = 5;
-
-// UpdateNote, because it contains all information we need and can be converted to an AddNote with only defaultable values missing.
-/// (note, seen)
-pub static NOTES: Mutex<Vec<(UpdateNote, bool)>> = Mutex::new(Vec::new());
+/// The AnkiConnect protocol version used, which is also the minimum supported version
+const ANKI_CONNECT_VERSION: u16 = 6;
+/// The maximum number of actions sent in one `multi` request
+const MAX_BATCH_SIZE: usize = 256;
 
 #[derive(Error, Debug)]
 pub enum RequestError {
@@ -34,6 +37,8 @@ pub enum RequestError {
     AnkiConncectRequest(ureq::Error),
     #[error("Failed to deserialize response: {0}")]
     Deserialisation(#[from] ureq::Error),
+    #[error("Failed to deserialize result: {0}")]
+    DeserializeResult(#[from] serde_json::Error),
     #[error("AnkiConnect returned error: {0}")]
     AnkiConnectError(String),
     // We would like to also include the value of the result here, but it would also need to implement Debug + Display etc. (which for example () doesn't)
@@ -43,33 +48,34 @@ pub enum RequestError {
     ErrorNorResult,
     #[error("AnkiConnect request returned an erroneous status code: {0}")]
     ErrStatus(StatusCode),
+    #[error("The batch request containing this action failed: {0}")]
+    BatchFailed(String),
 }
 
 trait Request: Debug + Serialize {
     type Output: DeserializeOwned + Debug = ();
     fn action_type() -> ActionType;
-    fn request(&self) -> Result<Self::Output, RequestError> {
-        #[derive(Serialize, Debug)]
-        #[serde(rename_all = "camelCase")]
-        struct Request<T> {
-            action: ActionType,
-            version: u8,
-            params: T,
+    fn action(&self) -> Action<&Self> {
+        Action {
+            action: Self::action_type(),
+            version: ANKI_CONNECT_VERSION,
+            key: CONFIG.anki_connect.api_key.as_deref(),
+            params: self,
         }
-
-        let request = || {
-            AGENT.post("http://localhost:8765").send_json(&Request {
-                action: Self::action_type(),
-                version: 6,
-                params: self,
-            })
-        };
-        let mut i = 0;
+    }
+    fn request(&self) -> Result<Self::Output, RequestError> {
+        let config = &CONFIG.anki_connect;
+        let request = || AGENT.post(&config.url).send_json(self.action());
+        let mut i: u8 = 0;
         let response = loop {
-            let timeout = Duration::from_millis(100 * 2_u64.pow(i.into()));
+            let timeout = Duration::from_millis(
+                config
+                    .initial_backoff_ms
+                    .saturating_mul(2_u64.saturating_pow(i.into())),
+            );
             match request() {
                 Ok(response) => break response,
-                Err(e) if i < MAX_BACKOFF => {
+                Err(e) if i < config.max_retries && is_retryable(Self::action_type(), &e) => {
                     warn!("AnkiConnect request failed (attempt {i}): {e}. Retrying in {timeout:?}");
                     sleep(timeout);
                 }
@@ -80,12 +86,7 @@ trait Request: Debug + Serialize {
 
         let response = if response.status().is_success() {
             let response: Response<Self::Output> = response.into_body().read_json()?;
-            match (response.result, response.error) {
-                (Some(result), None) => Ok(result),
-                (None, Some(error)) => Err(RequestError::AnkiConnectError(error)),
-                (Some(_), Some(error)) => Err(RequestError::ErrorAndResult { error }),
-                (None, None) => Err(RequestError::ErrorNorResult),
-            }
+            response.into_result()
         } else {
             Err(RequestError::ErrStatus(response.status()))
         };
@@ -94,15 +95,85 @@ trait Request: Debug + Serialize {
     }
 }
 
+/// Whether a failed request with `action` can be sent again.
+/// Actions that add something may have been carried out by anki despite a timeout or an erroneous status,
+/// so they are only retried if the request never reached anki
+fn is_retryable(action: ActionType, error: &ureq::Error) -> bool {
+    let idempotent = !matches!(
+        action,
+        ActionType::Multi
+            | ActionType::AddNote
+            | ActionType::CreateModel
+            | ActionType::ModelFieldAdd
+    );
+    idempotent
+        || match error {
+            ureq::Error::ConnectionFailed | ureq::Error::HostNotFound => true,
+            ureq::Error::Io(error) => error.kind() == io::ErrorKind::ConnectionRefused,
+            _ => false,
+        }
+}
+
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
+struct Action<T = serde_json::Value> {
+    action: ActionType,
+    version: u16,
+    /// required by AnkiConnect for every action (including those inside `multi`) if an api key is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<&'static str>,
+    params: T,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
 enum ActionType {
+    RequestPermission,
+    Version,
+    Multi,
     AddNote,
     DeleteNotes,
-    UpdateNote,
+    UpdateNoteFields,
+    AddTags,
+    RemoveTags,
+    FindNotes,
+    NotesModTime,
     NotesInfo,
     CreateDeck,
+    CardsInfo,
+    ChangeDeck,
+    ModelNames,
+    ModelFieldNames,
+    ModelFieldAdd,
+    CreateModel,
+    UpdateModelTemplates,
+    UpdateModelStyling,
     StoreMediaFile,
+    GetMediaFilesNames,
+    DeleteMediaFile,
+    Suspend,
+    Unsuspend,
+}
+
+#[derive(Serialize, Debug)]
+struct Tags {
+    notes: Vec<NoteId>,
+    /// space separated
+    tags: String,
+}
+#[derive(Serialize, Debug)]
+struct AddTags(Tags);
+impl Request for AddTags {
+    fn action_type() -> ActionType {
+        ActionType::AddTags
+    }
+}
+#[derive(Serialize, Debug)]
+struct RemoveTags(Tags);
+impl Request for RemoveTags {
+    fn action_type() -> ActionType {
+        ActionType::RemoveTags
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -117,16 +188,89 @@ impl<T: Request> Request for Note<T> {
     }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct UpdateNote {
     pub id: NoteId,
     pub fields: HashMap<String, String>,
     tags: Vec<String>,
 }
-impl Request for UpdateNote {
-    fn action_type() -> ActionType {
-        ActionType::UpdateNote
+impl UpdateNote {
+    /// The note with `id` that was just added from `cloze` with the file's `tags`
+    pub fn added(id: NoteId, cloze: &ClozeData, tags: &[String]) -> Self {
+        Self {
+            id,
+            fields: cloze_fields(cloze),
+            tags: note_tags(tags),
+        }
+    }
+    /// The value of the field `name`, or "" if the note doesn't have it
+    pub fn field(&self, name: &str) -> &str {
+        self.fields.get(name).map_or("", String::as_str)
+    }
+    /// The value of the configured text field
+    pub fn text(&self) -> &str {
+        self.field(&CONFIG.cloze_note_type.text_field)
+    }
+    /// The tags of the note managed by anksidian, including the ownership tag
+    fn managed_tags(&self) -> HashSet<&str> {
+        self.tags
+            .iter()
+            .map(String::as_str)
+            .filter(|tag| is_managed_tag(tag) || *tag == CONFIG.ownership_tag)
+            .collect()
+    }
+    /// Whether the note was created from a cloze in the file `source`
+    pub fn is_from(&self, source: &str) -> bool {
+        match &CONFIG.cloze_note_type.source_field {
+            Some(field) => self.field(field) == source,
+            // the source is appended to the text, followed by the breadcrumb
+            None => self
+                .text()
+                .rsplit_once("<br>")
+                .is_some_and(|(_, appended)| {
+                    appended
+                        .strip_prefix(source)
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with(" > "))
+                }),
+        }
+    }
+    /// Whether the note was tagged as orphaned by a previous run
+    fn is_orphaned(&self) -> bool {
+        self.tags.iter().any(|tag| tag == ORPHANED_TAG)
+    }
+    /// Whether the cards of the note were suspended by anksidian, when it was orphaned
+    fn is_suspended_orphan(&self) -> bool {
+        self.tags.iter().any(|tag| tag == SUSPENDED_TAG)
+    }
+    /// A readable one-line description of the note, for listing it to the user
+    pub fn summary(&self) -> String {
+        let note_type = &CONFIG.cloze_note_type;
+        let location: Vec<&str> = [&note_type.source_field, &note_type.breadcrumb_field]
+            .into_iter()
+            .flatten()
+            .map(|field| self.field(field))
+            .filter(|value| !value.is_empty())
+            .collect();
+        // the text field contains the source and breadcrumb after a <br> if they don't have their own fields
+        let text = self.text().replace("<br>", " | ");
+        if location.is_empty() {
+            text
+        } else {
+            format!("{}: {text}", location.join(" > "))
+        }
+    }
+    /// Whether updating the note with `cloze` and the file's `tags` wouldn't change anything
+    pub fn is_up_to_date(&self, cloze: &ClozeData, tags: &[String]) -> bool {
+        let tags = note_tags(tags);
+        cloze_fields(cloze)
+            .iter()
+            .all(|(name, value)| self.field(name) == value)
+            && cloze
+                .pictures
+                .iter()
+                .all(|picture| media::is_stored(&picture.filename))
+            && self.managed_tags() == tags.iter().map(String::as_str).collect()
     }
 }
 
@@ -135,90 +279,318 @@ struct Response<T> {
     result: Option<T>,
     error: Option<String>,
 }
+impl<T> Response<T> {
+    fn into_result(self) -> Result<T, RequestError> {
+        match (self.result, self.error) {
+            (Some(result), None) => Ok(result),
+            (None, Some(error)) => Err(RequestError::AnkiConnectError(error)),
+            (Some(_), Some(error)) => Err(RequestError::ErrorAndResult { error }),
+            (None, None) => Err(RequestError::ErrorNorResult),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[serde(transparent)]
 /// Contains a Unix Timestamp (so 13 decimal digits for the years 2001-2286)
 pub struct NoteId(pub u64);
 
-pub type LockNotesError = PoisonError<MutexGuard<'static, Vec<(UpdateNote, bool)>>>;
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct CardId(pub u64);
+
+/// Tags managed by anksidian are namespaced, so tags added in anki (like `leech` or `marked`) are left alone
+const MANAGED_TAG_PREFIX: &str = "obsidian::";
+/// Convert tags in a file (`#some/tag`) to the anki tags managed by anksidian (`obsidian::some::tag`)
+fn managed_tags(tags: &[String]) -> Vec<String> {
+    tags.iter()
+        .map(|tag| {
+            format!(
+                "{MANAGED_TAG_PREFIX}{}",
+                tag.trim_start_matches('#').replace('/', "::")
+            )
+        })
+        .collect()
+}
+/// All tags anksidian gives a note in a file with `tags`: the managed tags and the ownership tag
+fn note_tags(tags: &[String]) -> Vec<String> {
+    let mut tags = managed_tags(tags);
+    tags.push(CONFIG.ownership_tag.clone());
+    tags
+}
+/// Older versions of anksidian added tags verbatim, including their '#'
+fn is_managed_tag(tag: &str) -> bool {
+    tag.starts_with(MANAGED_TAG_PREFIX) || tag.starts_with('#')
+}
+#[test]
+fn test_managed_tags() {
+    assert_eq!(
+        managed_tags(&["#a".to_string(), "#b/c".to_string()]),
+        ["obsidian::a", "obsidian::b::c"]
+    );
+    assert!(is_managed_tag("#a") && !is_managed_tag("leech"));
+}
+
+/// For actions that return null, null on success
+fn ok_if_null(result: Result<(), RequestError>) -> Result<(), RequestError> {
+    match result {
+        Err(RequestError::ErrorNorResult) => Ok(()),
+        other => other,
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Field {
+    value: String,
+    // not needed:
+    // order: u8,
+}
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct NotesInfoNote {
+    note_id: NoteId,
+    model_name: String,
+    tags: Vec<String>,
+    fields: HashMap<String, Field>,
+    #[serde(rename = "mod")]
+    modified: u64,
+    cards: Vec<CardId>,
+    // not needed:
+    // profile: String,
+}
+impl NotesInfoNote {
+    fn update_note(&self) -> UpdateNote {
+        UpdateNote {
+            id: self.note_id,
+            fields: self
+                .fields
+                .iter()
+                .map(|(name, field)| (name.clone(), field.value.clone()))
+                .collect(),
+            tags: self.tags.clone(),
+        }
+    }
+}
+/// AnkiConnect returns an empty object for notes that don't exist
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum MaybeNotesInfoNote {
+    Note(NotesInfoNote),
+    Missing {},
+}
+/// Info about the notes with `ids` that exist, in the same order
+fn notes_info(ids: Vec<NoteId>) -> Result<Vec<NotesInfoNote>, RequestError> {
+    #[derive(Serialize, Debug)]
+    struct NotesInfo {
+        notes: Vec<NoteId>,
+    }
+    impl Request for NotesInfo {
+        type Output = Vec<MaybeNotesInfoNote>;
+        fn action_type() -> ActionType {
+            ActionType::NotesInfo
+        }
+    }
+
+    Ok(NotesInfo { notes: ids }
+        .request()?
+        .into_iter()
+        .filter_map(|note| match note {
+            MaybeNotesInfoNote::Note(note) => Some(note),
+            MaybeNotesInfoNote::Missing {} => None,
+        })
+        .collect())
+}
+
+/// Adds the notes with `ids` to `sync_state`, if they exist and have the configured note type.
+/// Notes created before anksidian tagged its notes with the ownership tag are adopted this way, once their
+/// NoteID is found in a file, and are tagged when they are updated.
+/// Returns the ids of the notes that exist, but have another note type.
+pub fn adopt_notes(
+    ids: Vec<NoteId>,
+    sync_state: &mut SyncState,
+) -> Result<Vec<NoteId>, RequestError> {
+    let mut foreign = Vec::new();
+    for note in notes_info(ids)? {
+        if note.model_name == CONFIG.cloze_note_type.model {
+            debug!("Adopting note {:?}", note.note_id);
+            sync_state.insert(note.update_note(), note.cards);
+        } else {
+            foreign.push(note.note_id);
+        }
+    }
+    Ok(foreign)
+}
+
+/// Local copy of the managed notes, so only notes modified since the last run have to be fetched
+#[derive(Serialize, Deserialize, Default)]
+struct NotesMirror {
+    /// the query the notes were found with, as changing the config changes which notes are managed
+    query: String,
+    notes: HashMap<NoteId, MirroredNote>,
+}
+#[derive(Serialize, Deserialize)]
+struct MirroredNote {
+    /// modification time in anki
+    modified: u64,
+    note: UpdateNote,
+    #[serde(default)]
+    cards: Vec<CardId>,
+}
+impl Cache for NotesMirror {
+    const FILE_NAME: &'static str = "notes_mirror.json";
+}
 
 #[derive(Error, Debug)]
 pub enum InitializeNotesError {
     #[error("Failed to request notes: {0}")]
     Request(#[from] RequestError),
-    #[error("Failed to lock NOTES: {0}")]
-    Lock(#[from] LockNotesError),
 }
-pub fn initialize_notes() -> Result<(), InitializeNotesError> {
-    #[derive(Deserialize, Debug)]
-    #[serde(rename_all = "camelCase")]
-    struct Field {
-        value: String,
-        // not needed:
-        // order: u8,
+pub fn initialize_notes() -> Result<SyncState, InitializeNotesError> {
+    #[derive(Serialize, Debug)]
+    struct FindNotes {
+        query: String,
+    }
+    impl Request for FindNotes {
+        type Output = Vec<NoteId>;
+        fn action_type() -> ActionType {
+            ActionType::FindNotes
+        }
     }
     #[derive(Deserialize, Debug)]
     #[serde(rename_all = "camelCase")]
-    struct NotesInfoNote {
+    struct NoteModTime {
         note_id: NoteId,
-        model_name: String,
-        tags: Vec<String>,
-        fields: HashMap<String, Field>, // not needed:
-                                        // profile: String,
-                                        // mod: u64,
-                                        // cards: Vec<u64>,
+        #[serde(rename = "mod")]
+        modified: u64,
     }
     #[derive(Serialize, Debug)]
-    struct Query {
-        query: String,
+    struct NotesModTime {
+        notes: Vec<NoteId>,
     }
-    impl Request for Query {
-        type Output = Vec<NotesInfoNote>;
+    impl Request for NotesModTime {
+        type Output = Vec<NoteModTime>;
         fn action_type() -> ActionType {
-            ActionType::NotesInfo
+            ActionType::NotesModTime
         }
     }
+    // notes are found by the ownership tag, wherever they live
+    let query = format!(
+        "\"tag:{}\" \"note:{}\"",
+        CONFIG.ownership_tag, CONFIG.cloze_note_type.model
+    );
 
-    let request = Query {
-        query: CONFIG
-            .path_to_deck
-            .iter()
-            .map(|mapping| format!("\"deck:{}\"", mapping.deck))
-            .intersperse("OR".to_string())
-            .collect(),
+    let mut mirror = match NotesMirror::load() {
+        Ok(mirror) if mirror.query == query => mirror,
+        Ok(_) => NotesMirror::default(),
+        Err(error) => {
+            warn!("Failed to load notes mirror, fetching all notes: {error}");
+            NotesMirror::default()
+        }
     };
-    let result = request.request()?;
 
-    let notes = result
+    let ids = FindNotes {
+        query: query.clone(),
+    }
+    .request()?;
+    let mod_times = NotesModTime { notes: ids.clone() }.request()?;
+    let changed: Vec<NoteId> = mod_times
         .into_iter()
-        .filter(|note| note.model_name == "Cloze")
-        .map(|note| {
-            (
-                UpdateNote {
-                    id: note.note_id,
-                    fields: note.fields.into_iter().map(|(k, v)| (k, v.value)).collect(),
-                    tags: note.tags,
-                },
-                false,
-            )
+        .filter(|mod_time| {
+            mirror
+                .notes
+                .get(&mod_time.note_id)
+                // mirrors of older versions didn't contain the cards
+                .is_none_or(|mirrored| {
+                    mirrored.modified != mod_time.modified || mirrored.cards.is_empty()
+                })
         })
+        .map(|mod_time| mod_time.note_id)
         .collect();
-    *NOTES.lock()? = notes;
+    debug!(
+        "{} of {} notes changed since the last run",
+        changed.len(),
+        ids.len()
+    );
 
-    Ok(())
+    if !changed.is_empty() {
+        for note in notes_info(changed)? {
+            mirror.notes.insert(
+                note.note_id,
+                MirroredNote {
+                    modified: note.modified,
+                    note: note.update_note(),
+                    cards: note.cards,
+                },
+            );
+        }
+    }
+
+    // drop notes that were deleted or are no longer owned
+    let mut notes = Vec::with_capacity(ids.len());
+    let mut remaining = HashMap::with_capacity(ids.len());
+    for id in ids {
+        if let Some(mirrored) = mirror.notes.remove(&id) {
+            notes.push((mirrored.note.clone(), mirrored.cards.clone()));
+            remaining.insert(id, mirrored);
+        }
+    }
+    mirror.notes = remaining;
+    mirror.query = query;
+    if let Err(error) = mirror.save() {
+        warn!("Failed to save notes mirror: {error}");
+    }
+
+    Ok(SyncState::new(notes))
 }
 
+/// What to do with managed notes whose cloze wasn't seen during the run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OrphanPolicy {
+    /// list them and ask once whether to delete them
+    #[default]
+    Ask,
+    Delete,
+    Keep,
+    /// tag them with `ORPHANED_TAG`
+    Tag,
+    /// tag them with `ORPHANED_TAG` and suspend their cards
+    Suspend,
+}
+#[derive(Error, Debug)]
+#[error("unknown orphan policy '{0}', expected one of ask, delete, keep, tag, suspend")]
+pub struct UnknownOrphanPolicy(String);
+impl FromStr for OrphanPolicy {
+    type Err = UnknownOrphanPolicy;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ask" => Ok(Self::Ask),
+            "delete" => Ok(Self::Delete),
+            "keep" => Ok(Self::Keep),
+            "tag" => Ok(Self::Tag),
+            "suspend" => Ok(Self::Suspend),
+            other => Err(UnknownOrphanPolicy(other.to_string())),
+        }
+    }
+}
+
+/// The tag given to orphaned notes by the `tag` and `suspend` policies
+pub const ORPHANED_TAG: &str = "anksidian::orphaned";
+/// The tag given to orphaned notes whose cards were suspended by the `suspend` policy,
+/// so that suspensions made in anki aren't undone when they are revived
+const SUSPENDED_TAG: &str = "anksidian::suspended";
+
 #[derive(Error, Debug)]
 pub enum UnseenNotesError {
-    #[error("Failed to delete note: {0}")]
+    #[error("Failed to handle orphaned notes: {0}")]
     Request(#[from] RequestError),
     #[error("Reading from stdin failed: {0}")]
     Stdin(#[from] std::io::Error),
-    #[error("Failed to lock NOTES: {0}")]
-    Lock(#[from] LockNotesError),
 }
-pub fn handle_unseen_notes() -> Result<(), UnseenNotesError> {
+pub fn handle_unseen_notes(
+    sync_state: &SyncState,
+    policy: OrphanPolicy,
+) -> Result<(), UnseenNotesError> {
     #[derive(Serialize, Debug)]
     #[serde(rename_all = "camelCase")]
     struct DeleteNotes {
@@ -229,141 +601,608 @@ pub fn handle_unseen_notes() -> Result<(), UnseenNotesError> {
             ActionType::DeleteNotes
         }
     }
+    #[derive(Serialize, Debug)]
+    struct Suspend {
+        cards: Vec<CardId>,
+    }
+    impl Request for Suspend {
+        type Output = bool;
+        fn action_type() -> ActionType {
+            ActionType::Suspend
+        }
+    }
 
-    let mut buf = String::new();
-    for (note, seen) in NOTES.lock()?.iter() {
-        if !seen {
-            println!(
-                "Note present in Anki but not seen during run. Delete from Anki? (y/n)\n{note:?}"
-            );
+    let orphans: Vec<&UpdateNote> = sync_state.unseen().collect();
+    if orphans.is_empty() || policy == OrphanPolicy::Keep {
+        return Ok(());
+    }
+    let notes: Vec<NoteId> = orphans.iter().map(|note| note.id).collect();
+    match policy {
+        OrphanPolicy::Keep => {}
+        OrphanPolicy::Ask => {
+            println!("Notes present in Anki but not seen during run:");
+            for note in &orphans {
+                println!("- {}", note.summary());
+            }
+            println!("Delete these {} notes from Anki? (y/n)", orphans.len());
+            let mut buf = String::new();
             loop {
                 buf.clear();
-                stdin().read_line(&mut buf)?;
+                // treat end of input as no, so that anksidian doesn't hang without a terminal
+                if stdin().read_line(&mut buf)? == 0 {
+                    break;
+                }
                 match buf.trim() {
                     "Y" | "y" | "Yes" | "yes" => {
-                        let request = DeleteNotes {
-                            notes: vec![note.id],
-                        };
-                        match request.request() {
-                            // return null, null on success
-                            Err(RequestError::ErrorNorResult) => {}
-                            Err(other) => Err(other)?,
-                            Ok(_) => {}
-                        }
+                        ok_if_null(DeleteNotes { notes }.request())?;
                         break;
                     }
-                    "N" | "n" | "No" | "no" => {
-                        break;
-                    }
-                    other => println!("unknown option '{other}"),
+                    "N" | "n" | "No" | "no" => break,
+                    other => println!("unknown option '{other}'"),
+                }
+            }
+        }
+        OrphanPolicy::Delete => {
+            ok_if_null(DeleteNotes { notes }.request())?;
+            println!("Deleted {} orphaned notes", orphans.len());
+        }
+        OrphanPolicy::Tag | OrphanPolicy::Suspend => {
+            let count = notes.len();
+            let tags = if policy == OrphanPolicy::Suspend {
+                format!("{ORPHANED_TAG} {SUSPENDED_TAG}")
+            } else {
+                ORPHANED_TAG.to_string()
+            };
+            ok_if_null(AddTags(Tags { notes, tags }).request())?;
+            if policy == OrphanPolicy::Suspend {
+                let cards: Vec<CardId> = orphans
+                    .iter()
+                    .flat_map(|note| sync_state.cards(note.id))
+                    .copied()
+                    .collect();
+                if !cards.is_empty() {
+                    Suspend { cards }.request()?;
                 }
+                println!("Tagged and suspended {count} orphaned notes");
+            } else {
+                println!("Tagged {count} orphaned notes");
             }
         }
     }
     Ok(())
 }
 
+/// Removes `ORPHANED_TAG` from the notes seen again during this run and unsuspends the cards of the ones
+/// suspended by anksidian, returning the number of such notes
+pub fn revive_orphans(sync_state: &SyncState) -> Result<usize, RequestError> {
+    #[derive(Serialize, Debug)]
+    struct Unsuspend {
+        cards: Vec<CardId>,
+    }
+    impl Request for Unsuspend {
+        type Output = bool;
+        fn action_type() -> ActionType {
+            ActionType::Unsuspend
+        }
+    }
+
+    let notes: Vec<NoteId> = sync_state
+        .seen()
+        .filter(|note| note.is_orphaned())
+        .map(|note| note.id)
+        .collect();
+    if notes.is_empty() {
+        return Ok(0);
+    }
+    // cards suspended in anki, e.g. leeches, stay suspended
+    let cards: Vec<CardId> = sync_state
+        .seen()
+        .filter(|note| note.is_orphaned() && note.is_suspended_orphan())
+        .flat_map(|note| sync_state.cards(note.id))
+        .copied()
+        .collect();
+    let count = notes.len();
+    ok_if_null(
+        RemoveTags(Tags {
+            notes,
+            tags: format!("{ORPHANED_TAG} {SUSPENDED_TAG}"),
+        })
+        .request(),
+    )?;
+    if !cards.is_empty() {
+        Unsuspend { cards }.request()?;
+    }
+    Ok(count)
+}
+
+/// The text field of the note for `cloze`, which includes its source and breadcrumb unless they have their own fields
+pub fn cloze_text(cloze: &ClozeData) -> String {
+    let note_type = &CONFIG.cloze_note_type;
+    let appended: Vec<&str> = [
+        (&note_type.source_field, cloze.source.as_str()),
+        (&note_type.breadcrumb_field, cloze.breadcrumb.as_str()),
+    ]
+    .into_iter()
+    .filter(|(field, part)| field.is_none() && !part.is_empty())
+    .map(|(_, part)| part)
+    .collect();
+
+    let mut text = cloze.contents.clone();
+    if !appended.is_empty() {
+        text.push_str("<br>");
+        text.push_str(&appended.join(" > "));
+    }
+    text
+}
+
+/// All fields of the note for `cloze`, named as configured in `cloze_note_type`
+fn cloze_fields(cloze: &ClozeData) -> HashMap<String, String> {
+    let note_type = &CONFIG.cloze_note_type;
+    let mut fields = HashMap::from([
+        (note_type.text_field.clone(), cloze_text(cloze)),
+        (note_type.extra_field.clone(), back_extra(&cloze.pictures)),
+    ]);
+    if let Some(field) = &note_type.source_field {
+        fields.insert(field.clone(), cloze.source.clone());
+    }
+    if let Some(field) = &note_type.breadcrumb_field {
+        fields.insert(field.clone(), cloze.breadcrumb.clone());
+    }
+    if let Some(field) = &note_type.link_field {
+        fields.insert(field.clone(), cloze.obsidian_link.clone());
+    }
+    fields
+}
+
 /// Include pictures on the back
 fn back_extra(pictures: &[Picture]) -> String {
     pictures
         .iter()
+        .filter(|picture| picture.on_back)
         .map(|picture| format!("<img src=\"{}\">", picture.filename))
         .collect()
 }
 
-pub fn add_cloze_note(
-    cloze: ClozeData,
-    tags: Vec<String>,
-    deck: &str,
-) -> Result<NoteId, RequestError> {
+#[derive(Serialize, Debug)]
+struct Multi {
+    actions: Vec<Action>,
+}
+impl Request for Multi {
+    type Output = Vec<Response<serde_json::Value>>;
+    fn action_type() -> ActionType {
+        ActionType::Multi
+    }
+}
+
+enum Operation {
+    Add,
+    Update(NoteId),
+}
+
+/// Note additions and updates, that are sent to AnkiConnect together using `multi`
+#[derive(Default)]
+pub struct NoteBatch {
+    actions: Vec<Action>,
+    /// for every operation, the range of its actions and its pictures. The result of the last action is the operation's result
+    operations: Vec<(Operation, Range<usize>, Vec<Picture>)>,
+    /// media files already stored by this batch
+    stored_media: HashSet<String>,
+}
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+enum DuplicateScope {
+    Deck,
+}
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AddNoteOptions {
+    allow_duplicate: bool,
+    duplicate_scope: DuplicateScope,
+}
+impl AddNoteOptions {
+    /// Separate duplicate clozes are identical notes, which anki would reject by default
+    fn new(duplicate_clozes: DuplicateClozes) -> Self {
+        Self {
+            allow_duplicate: duplicate_clozes == DuplicateClozes::Separate,
+            duplicate_scope: DuplicateScope::Deck,
+        }
+    }
+}
+#[test]
+fn test_add_note_options() {
+    let options = |duplicate_clozes| {
+        serde_json::to_value(AddNoteOptions::new(duplicate_clozes))
+            .expect("Options should serialize to json")
+    };
+    assert_eq!(
+        options(DuplicateClozes::Dedupe),
+        serde_json::json!({"allowDuplicate": false, "duplicateScope": "deck"})
+    );
+    assert_eq!(
+        options(DuplicateClozes::Separate),
+        serde_json::json!({"allowDuplicate": true, "duplicateScope": "deck"})
+    );
+}
+
+impl NoteBatch {
+    fn push<R: Request>(&mut self, request: R) {
+        let action = request.action();
+        self.actions.push(Action {
+            action: action.action,
+            version: action.version,
+            key: action.key,
+            params: serde_json::to_value(action.params).expect("Requests should serialize to json"),
+        });
+    }
+
+    pub fn add_cloze_note(&mut self, cloze: &ClozeData, tags: &[String], deck: &str) {
+        #[derive(Serialize, Debug)]
+        #[serde(rename_all = "camelCase")]
+        struct AddNote {
+            deck_name: String,
+            model_name: String,
+            fields: HashMap<String, String>,
+            options: AddNoteOptions,
+            tags: Vec<String>,
+        }
+        impl Request for AddNote {
+            type Output = NoteId;
+            fn action_type() -> ActionType {
+                ActionType::AddNote
+            }
+        }
+
+        let start = self.actions.len();
+        self.store_pictures(&cloze.pictures);
+        let add_note = AddNote {
+            deck_name: deck.to_string(),
+            model_name: CONFIG.cloze_note_type.model.clone(),
+            fields: cloze_fields(cloze),
+            options: AddNoteOptions::new(CONFIG.duplicate_clozes),
+            tags: note_tags(tags),
+        };
+        self.push(Note { note: add_note });
+        self.operations.push((
+            Operation::Add,
+            start..self.actions.len(),
+            cloze.pictures.clone(),
+        ));
+    }
+
+    /// Update `note` to the contents of `cloze` and the file's `tags`, leaving tags not managed by anksidian alone
+    pub fn update_cloze_note(&mut self, cloze: &ClozeData, note: &UpdateNote, tags: &[String]) {
+        #[derive(Serialize, Debug)]
+        struct UpdateNoteFields {
+            id: NoteId,
+            fields: HashMap<String, String>,
+        }
+        impl Request for UpdateNoteFields {
+            fn action_type() -> ActionType {
+                ActionType::UpdateNoteFields
+            }
+        }
+        let start = self.actions.len();
+        self.store_pictures(&cloze.pictures);
+        // update tags
+        let id = note.id;
+        let current = note.managed_tags();
+        let tags = note_tags(tags);
+        let wanted: HashSet<&str> = tags.iter().map(String::as_str).collect();
+        let to_remove: Vec<&str> = current.difference(&wanted).copied().collect();
+        let to_add: Vec<&str> = wanted.difference(&current).copied().collect();
+        if !to_remove.is_empty() {
+            self.push(RemoveTags(Tags {
+                notes: vec![id],
+                tags: to_remove.join(" "),
+            }));
+        }
+        if !to_add.is_empty() {
+            self.push(AddTags(Tags {
+                notes: vec![id],
+                tags: to_add.join(" "),
+            }));
+        }
+        // update note, last, as its result is the result of the operation
+        self.push(Note {
+            note: UpdateNoteFields {
+                id,
+                fields: cloze_fields(cloze),
+            },
+        });
+        self.operations.push((
+            Operation::Update(id),
+            start..self.actions.len(),
+            cloze.pictures.clone(),
+        ));
+    }
+
+    /// Store the pictures not yet stored in anki
+    fn store_pictures(&mut self, pictures: &[Picture]) {
+        #[derive(Serialize, Debug)]
+        struct StorePicture {
+            path: PathBuf,
+            filename: String,
+        }
+        impl Request for StorePicture {
+            type Output = String;
+            fn action_type() -> ActionType {
+                ActionType::StoreMediaFile
+            }
+        }
+
+        for picture in pictures {
+            if media::is_stored(&picture.filename)
+                || !self.stored_media.insert(picture.filename.clone())
+            {
+                continue;
+            }
+            self.push(StorePicture {
+                path: picture.path.clone(),
+                filename: picture.filename.clone(),
+            });
+        }
+    }
+
+    /// Send all operations, returning the id of the added or updated note for each operation, in the order they were pushed
+    ///
+    /// If the request of a chunk fails, all operations with actions in that chunk fail, the other chunks are unaffected
+    pub fn send(self) -> Vec<Result<NoteId, RequestError>> {
+        let mut results = Vec::with_capacity(self.actions.len());
+        let mut actions = self.actions.into_iter().peekable();
+        while actions.peek().is_some() {
+            let multi = Multi {
+                actions: actions.by_ref().take(MAX_BATCH_SIZE).collect(),
+            };
+            let chunk_len = multi.actions.len();
+            match multi.request() {
+                Ok(responses) => results.extend(responses.into_iter().map(Response::into_result)),
+                Err(error) => {
+                    let error = error.to_string();
+                    results.extend(
+                        (0..chunk_len).map(|_| Err(RequestError::BatchFailed(error.clone()))),
+                    );
+                }
+            }
+        }
+
+        let mut results = results.into_iter();
+        self.operations
+            .into_iter()
+            .map(|(operation, range, pictures)| {
+                // consume all results of the operation, even if one of them failed
+                let operation_results: Vec<_> = results.by_ref().take(range.len()).collect();
+                let mut last = Err(RequestError::ErrorNorResult);
+                for result in operation_results {
+                    match result {
+                        // return null, null on success
+                        Err(RequestError::ErrorNorResult) => last = Ok(serde_json::Value::Null),
+                        Err(other) => return Err(other),
+                        Ok(value) => last = Ok(value),
+                    }
+                }
+                let value = last?;
+                for picture in pictures {
+                    media::record_stored(picture.filename, picture.path);
+                }
+                match operation {
+                    Operation::Add => Ok(serde_json::from_value(value)?),
+                    Operation::Update(id) => Ok(id),
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CheckConnectionError {
+    #[error(
+        "Failed to reach AnkiConnect at {url}, is Anki running with AnkiConnect installed? {error}"
+    )]
+    Unreachable { url: String, error: RequestError },
+    #[error("AnkiConnect denied permission, allow anksidian in the dialog shown by Anki")]
+    PermissionDenied,
+    #[error(
+        "AnkiConnect requires an api key, set `anki_connect.api_key` in the config or ANKSIDIAN_ANKI_CONNECT_KEY"
+    )]
+    MissingApiKey,
+    #[error("AnkiConnect rejected the request: {0}")]
+    Request(#[from] RequestError),
+    #[error(
+        "AnkiConnect version {0} is too old, version {} or newer is required",
+        ANKI_CONNECT_VERSION
+    )]
+    TooOld(u16),
+}
+/// Check that AnkiConnect is reachable, grants access and is recent enough
+pub fn check_connection() -> Result<(), CheckConnectionError> {
     #[derive(Serialize, Debug)]
+    struct RequestPermission {}
+    #[derive(Deserialize, Debug)]
     #[serde(rename_all = "camelCase")]
-    enum DuplicateScope {
-        Deck,
+    struct Permission {
+        permission: String,
+        #[serde(default)]
+        require_api_key: bool,
+    }
+    impl Request for RequestPermission {
+        type Output = Permission;
+        fn action_type() -> ActionType {
+            ActionType::RequestPermission
+        }
     }
     #[derive(Serialize, Debug)]
-    #[serde(rename_all = "camelCase")]
-    struct Options {
-        allow_duplicate: bool,
-        duplicate_scope: DuplicateScope,
+    struct Version {}
+    impl Request for Version {
+        type Output = u16;
+        fn action_type() -> ActionType {
+            ActionType::Version
+        }
+    }
+
+    let permission =
+        RequestPermission {}
+            .request()
+            .map_err(|error| CheckConnectionError::Unreachable {
+                url: CONFIG.anki_connect.url.clone(),
+                error,
+            })?;
+    if permission.permission != "granted" {
+        return Err(CheckConnectionError::PermissionDenied);
     }
+    if permission.require_api_key && CONFIG.anki_connect.api_key.is_none() {
+        return Err(CheckConnectionError::MissingApiKey);
+    }
+
+    let version = Version {}.request()?;
+    if version < ANKI_CONNECT_VERSION {
+        return Err(CheckConnectionError::TooOld(version));
+    }
+    Ok(())
+}
+
+pub struct DeckMove {
+    pub from: String,
+    pub to: String,
+}
+/// Move the cards of every note in `targets` to its target deck, unless they already are in it or one of its subdecks.
+/// Returns the move of each target, if it was moved
+pub fn move_to_decks(targets: &[(&[CardId], &str)]) -> Result<Vec<Option<DeckMove>>, RequestError> {
     #[derive(Serialize, Debug)]
+    struct CardsInfo {
+        cards: Vec<CardId>,
+    }
+    #[derive(Deserialize, Debug)]
     #[serde(rename_all = "camelCase")]
-    struct AddNote {
+    struct CardInfo {
+        card_id: CardId,
         deck_name: String,
-        model_name: String,
-        fields: HashMap<String, String>,
-        options: Options,
-        tags: Vec<String>,
-        picture: Vec<Picture>,
-    }
-    impl Request for AddNote {
-        type Output = NoteId;
+    }
+    impl Request for CardsInfo {
+        type Output = Vec<CardInfo>;
         fn action_type() -> ActionType {
-            ActionType::AddNote
+            ActionType::CardsInfo
+        }
+    }
+    #[derive(Serialize, Debug)]
+    struct ChangeDeck<'a> {
+        cards: Vec<CardId>,
+        deck: &'a str,
+    }
+    impl Request for ChangeDeck<'_> {
+        fn action_type() -> ActionType {
+            ActionType::ChangeDeck
         }
     }
 
-    let add_note = AddNote {
-        deck_name: deck.to_string(),
-        model_name: "Cloze".to_string(),
-        fields: HashMap::from([
-            ("Text".to_string(), cloze.contents.clone()),
-            ("Back Extra".to_string(), back_extra(&cloze.pictures)),
-        ]),
-        options: Options {
-            allow_duplicate: false,
-            duplicate_scope: DuplicateScope::Deck,
-        },
-        tags: tags.clone(),
-        picture: cloze.pictures,
-    };
-    let request = Note { note: add_note };
+    let cards: Vec<CardId> = targets
+        .iter()
+        .flat_map(|(cards, _)| cards.iter().copied())
+        .collect();
+    if cards.is_empty() {
+        return Ok(targets.iter().map(|_| None).collect());
+    }
+    let decks: HashMap<CardId, String> = CardsInfo { cards }
+        .request()?
+        .into_iter()
+        .map(|info| (info.card_id, info.deck_name))
+        .collect();
 
-    request.request()
+    let mut moves = Vec::with_capacity(targets.len());
+    let mut to_move: HashMap<&str, Vec<CardId>> = HashMap::new();
+    for (cards, deck) in targets {
+        let misplaced: Vec<(CardId, &String)> = cards
+            .iter()
+            .filter_map(|card| Some((*card, decks.get(card)?)))
+            .filter(|(_, current)| {
+                current.as_str() != *deck && !current.starts_with(&format!("{deck}::"))
+            })
+            .collect();
+        moves.push(misplaced.first().map(|(_, from)| DeckMove {
+            from: from.to_string(),
+            to: deck.to_string(),
+        }));
+        to_move
+            .entry(*deck)
+            .or_default()
+            .extend(misplaced.into_iter().map(|(card, _)| card));
+    }
+    for (deck, cards) in to_move {
+        if !cards.is_empty() {
+            ok_if_null(ChangeDeck { cards, deck }.request())?;
+        }
+    }
+    Ok(moves)
 }
 
-pub fn update_cloze_note(cloze: ClozeData, tags: Vec<String>) -> Result<(), RequestError> {
+/// Names of the media files stored in anki by anksidian
+pub fn stored_media_names() -> Result<HashSet<String>, RequestError> {
     #[derive(Serialize, Debug)]
-    struct StorePicture {
-        path: PathBuf,
-        filename: String,
+    struct GetMediaFilesNames {
+        pattern: &'static str,
     }
-    impl Request for StorePicture {
-        type Output = String;
+    impl Request for GetMediaFilesNames {
+        type Output = HashSet<String>;
         fn action_type() -> ActionType {
-            ActionType::StoreMediaFile
+            ActionType::GetMediaFilesNames
         }
     }
 
-    let back_extra = back_extra(&cloze.pictures);
+    GetMediaFilesNames {
+        pattern: "anksidian-*",
+    }
+    .request()
+}
 
-    // store pictures to anki
-    for picture in cloze.pictures {
-        StorePicture {
-            path: picture.path,
-            filename: picture.filename,
+/// Whether any note in the whole collection, not only the ones managed by anksidian, contains `filename`
+pub fn is_media_referenced(filename: &str) -> Result<bool, RequestError> {
+    #[derive(Serialize, Debug)]
+    struct FindNotes {
+        query: String,
+    }
+    impl Request for FindNotes {
+        type Output = Vec<NoteId>;
+        fn action_type() -> ActionType {
+            ActionType::FindNotes
         }
-        .request()?;
     }
-    // update note
-    let update_note = UpdateNote {
-        fields: HashMap::from([
-            ("Text".to_string(), cloze.contents),
-            ("Back Extra".to_string(), back_extra),
-        ]),
-        id: cloze
-            .note_id
-            .expect("Note id should be present in update path"),
-        tags,
-    };
-    let request = Note { note: update_note };
 
-    match request.request() {
-        // return null, null on success
-        Err(RequestError::ErrorNorResult) => Ok(()),
-        other => other,
+    let notes = FindNotes {
+        query: format!("\"{}\"", escape_search(filename)),
     }
+    .request()?;
+    Ok(!notes.is_empty())
+}
+
+/// Escapes the characters with a special meaning in anki's search syntax
+fn escape_search(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for char in text.chars() {
+        if matches!(char, '\\' | '"' | '*' | '_' | ':') {
+            escaped.push('\\');
+        }
+        escaped.push(char);
+    }
+    escaped
+}
+#[test]
+fn test_escape_search() {
+    assert_eq!(escape_search("anksidian-a_b*.svg"), r"anksidian-a\_b\*.svg");
+    assert_eq!(escape_search(r#"a:"\"#), r#"a\:\"\\"#);
+}
+
+/// Deletes the file `filename` from anki's media folder
+pub fn delete_media_file(filename: &str) -> Result<(), RequestError> {
+    #[derive(Serialize, Debug)]
+    struct DeleteMediaFile<'a> {
+        filename: &'a str,
+    }
+    impl Request for DeleteMediaFile<'_> {
+        fn action_type() -> ActionType {
+            ActionType::DeleteMediaFile
+        }
+    }
+
+    ok_if_null(DeleteMediaFile { filename }.request())
 }
 
 /// Ensures that the deck `DECK` exists
@@ -385,3 +1224,186 @@ pub fn ensure_deck_exists(deck: &str) -> Result<(), RequestError> {
     };
     request.request().map(|_: u64| {})
 }
+
+/// The name of the note type created by anksidian
+pub const ANKSIDIAN_MODEL: &str = "Anksidian Cloze";
+const ANKSIDIAN_MODEL_FIELDS: [&str; 5] =
+    ["Text", "Back Extra", "Source", "Breadcrumb", "ObsidianLink"];
+const ANKSIDIAN_MODEL_TEMPLATE: &str = "Cloze";
+const ANKSIDIAN_MODEL_FRONT: &str = "{{cloze:Text}}";
+const ANKSIDIAN_MODEL_BACK: &str = "{{cloze:Text}}\n\
+    {{#Back Extra}}<br>{{Back Extra}}{{/Back Extra}}\n\
+    <div class=\"anksidian-source\">\
+    <a href=\"{{ObsidianLink}}\">{{Source}}</a>\
+    {{#Breadcrumb}} &gt; {{Breadcrumb}}{{/Breadcrumb}}\
+    </div>";
+const ANKSIDIAN_MODEL_CSS: &str = ".card {
+    font-family: arial;
+    font-size: 20px;
+    text-align: center;
+    color: black;
+    background-color: white;
+}
+.cloze {
+    font-weight: bold;
+    color: blue;
+}
+.nightMode .cloze {
+    color: lightblue;
+}
+.anksidian-source {
+    margin-top: 1em;
+    font-size: 14px;
+    color: grey;
+}
+";
+
+/// Create the anksidian note type, or bring its fields, templates and styling up to date
+pub fn ensure_model_exists() -> Result<(), RequestError> {
+    #[derive(Serialize, Debug)]
+    struct ModelNames {}
+    impl Request for ModelNames {
+        type Output = Vec<String>;
+        fn action_type() -> ActionType {
+            ActionType::ModelNames
+        }
+    }
+    #[derive(Serialize, Debug)]
+    #[serde(rename_all = "PascalCase")]
+    struct CardTemplate {
+        name: &'static str,
+        front: &'static str,
+        back: &'static str,
+    }
+    #[derive(Serialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct CreateModel {
+        model_name: &'static str,
+        in_order_fields: [&'static str; 5],
+        css: &'static str,
+        is_cloze: bool,
+        card_templates: [CardTemplate; 1],
+    }
+    impl Request for CreateModel {
+        type Output = serde_json::Value;
+        fn action_type() -> ActionType {
+            ActionType::CreateModel
+        }
+    }
+    #[derive(Serialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct ModelFieldNames {
+        model_name: &'static str,
+    }
+    impl Request for ModelFieldNames {
+        type Output = Vec<String>;
+        fn action_type() -> ActionType {
+            ActionType::ModelFieldNames
+        }
+    }
+    #[derive(Serialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct ModelFieldAdd {
+        model_name: &'static str,
+        field_name: &'static str,
+        index: usize,
+    }
+    impl Request for ModelFieldAdd {
+        fn action_type() -> ActionType {
+            ActionType::ModelFieldAdd
+        }
+    }
+    #[derive(Serialize, Debug)]
+    struct Templates {
+        #[serde(rename = "Front")]
+        front: &'static str,
+        #[serde(rename = "Back")]
+        back: &'static str,
+    }
+    #[derive(Serialize, Debug)]
+    struct TemplatesModel {
+        name: &'static str,
+        templates: HashMap<&'static str, Templates>,
+    }
+    #[derive(Serialize, Debug)]
+    struct UpdateModelTemplates {
+        model: TemplatesModel,
+    }
+    impl Request for UpdateModelTemplates {
+        fn action_type() -> ActionType {
+            ActionType::UpdateModelTemplates
+        }
+    }
+    #[derive(Serialize, Debug)]
+    struct StylingModel {
+        name: &'static str,
+        css: &'static str,
+    }
+    #[derive(Serialize, Debug)]
+    struct UpdateModelStyling {
+        model: StylingModel,
+    }
+    impl Request for UpdateModelStyling {
+        fn action_type() -> ActionType {
+            ActionType::UpdateModelStyling
+        }
+    }
+    let model_names = ModelNames {}.request()?;
+    if !model_names.iter().any(|name| name == ANKSIDIAN_MODEL) {
+        CreateModel {
+            model_name: ANKSIDIAN_MODEL,
+            in_order_fields: ANKSIDIAN_MODEL_FIELDS,
+            css: ANKSIDIAN_MODEL_CSS,
+            is_cloze: true,
+            card_templates: [CardTemplate {
+                name: ANKSIDIAN_MODEL_TEMPLATE,
+                front: ANKSIDIAN_MODEL_FRONT,
+                back: ANKSIDIAN_MODEL_BACK,
+            }],
+        }
+        .request()?;
+        return Ok(());
+    }
+
+    // fields added in newer versions of anksidian
+    let field_names = ModelFieldNames {
+        model_name: ANKSIDIAN_MODEL,
+    }
+    .request()?;
+    for (index, field_name) in ANKSIDIAN_MODEL_FIELDS.into_iter().enumerate() {
+        if !field_names.iter().any(|name| name == field_name) {
+            ok_if_null(
+                ModelFieldAdd {
+                    model_name: ANKSIDIAN_MODEL,
+                    field_name,
+                    index,
+                }
+                .request(),
+            )?;
+        }
+    }
+    ok_if_null(
+        UpdateModelTemplates {
+            model: TemplatesModel {
+                name: ANKSIDIAN_MODEL,
+                templates: HashMap::from([(
+                    ANKSIDIAN_MODEL_TEMPLATE,
+                    Templates {
+                        front: ANKSIDIAN_MODEL_FRONT,
+                        back: ANKSIDIAN_MODEL_BACK,
+                    },
+                )]),
+            },
+        }
+        .request(),
+    )?;
+    ok_if_null(
+        UpdateModelStyling {
+            model: StylingModel {
+                name: ANKSIDIAN_MODEL,
+                css: ANKSIDIAN_MODEL_CSS,
+            },
+        }
+        .request(),
+    )
+}
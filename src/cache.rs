@@ -0,0 +1,68 @@
+use serde::{Serialize, de::DeserializeOwned};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::BufWriter,
+    path::PathBuf,
+};
+use thiserror::Error;
+
+// Json files in anksidian's cache directory (~/.cache/anksidian on linux)
+
+#[derive(Error, Debug)]
+pub enum CacheLoadError {
+    #[error("Failed to get path to cache")]
+    GetPath,
+    #[error("Failed to open cache: {0}")]
+    Open(#[from] std::io::Error),
+    #[error("Failed to deserialize cache: {0}")]
+    Deserialize(#[from] serde_json::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum CacheSaveError {
+    #[error("Failed to get path to cache")]
+    GetPath,
+    #[error("Failed to create parent paths for the cache: {0}")]
+    CreateParents(std::io::Error),
+    #[error("Failed to open cache: {0}")]
+    Open(std::io::Error),
+    #[error("Failed to serialize cache: {0}")]
+    Serialize(#[from] serde_json::Error),
+}
+
+pub trait Cache: Serialize + DeserializeOwned + Default {
+    /// The name of the cache file inside the cache directory
+    const FILE_NAME: &'static str;
+
+    fn get_path() -> Option<PathBuf> {
+        Some(dirs::cache_dir()?.join("anksidian").join(Self::FILE_NAME))
+    }
+    /// Load the cache, or return an empty one if it doesn't exist yet
+    fn load() -> Result<Self, CacheLoadError> {
+        let path = Self::get_path().ok_or(CacheLoadError::GetPath)?;
+        if !path.exists() {
+            Ok(Self::default())
+        } else {
+            let file = File::open_buffered(&path).map_err(CacheLoadError::Open)?;
+            Ok(serde_json::from_reader(file)?)
+        }
+    }
+    fn save(&self) -> Result<(), CacheSaveError> {
+        let path = Self::get_path().ok_or(CacheSaveError::GetPath)?;
+        let parent = path.parent().expect("Path should have a parent");
+        if !parent.exists() {
+            fs::create_dir_all(parent).map_err(CacheSaveError::CreateParents)?;
+        }
+        let file = BufWriter::new(
+            OpenOptions::new()
+                .read(false)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .map_err(CacheSaveError::Open)?,
+        );
+        serde_json::to_writer(file, self)?;
+        Ok(())
+    }
+}
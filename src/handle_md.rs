@@ -1,19 +1,31 @@
 use crate::{
-    anki::{add_cloze_note, update_cloze_note, LockNotesError, NoteId, NOTES},
-    FileCache, CONFIG,
+    CONFIG, DuplicateClozes, MathDialect, MathOutput, PWD,
+    anki::{
+        CardId, DeckMove, NoteBatch, NoteId, UpdateNote, adopt_notes, cloze_text, move_to_decks,
+    },
+    cache::{Cache, CacheSaveError},
+    latex_macros::Macros,
+    media::media_name,
+    render_math::{self, RenderMathError},
+    sync_state::SyncState,
+    typst_math,
 };
-use log::{error, warn};
-use serde::Serialize;
+use blake3::Hasher;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
+    collections::{HashMap, HashSet},
     env::temp_dir,
     ffi::OsStr,
-    fmt::Write as _,
+    fmt::{self, Display, Write as _},
     fs::{self, create_dir_all},
     io::{self, Write as _},
+    ops::AddAssign,
     path::{Path, PathBuf},
-    process::{Command, ExitStatusError, Stdio},
+    process::{Command, ExitStatus, Stdio},
     string::FromUtf8Error,
+    sync::{LazyLock, Mutex, PoisonError},
 };
 use thiserror::Error;
 
@@ -41,6 +53,7 @@ type Newline = Or<(TStr<"\r">, TStr<"\n">, TStr<"\r\n">)>;
 // heading
 type Element = Or<(Code, Math, Link, Bold, Italic, char)>;
 type Heading = (
+    RemainingLength, // start, for error locations
     VecN<1, TStr<"#">>,
     TStr<" ">,
     Vec<(IsNot<Newline>, Element)>,
@@ -60,9 +73,13 @@ type Cloze = (
     TStr<"==">,
 );
 
+// The first cloze of a line, which may also be a math or code block containing a cloze
+type ClozeStart = Or<(Cloze, ClozedMath, ClozedCode)>;
+
 type ClozeLines = (
-    Vec<(IsNot<Or<(Cloze, Newline)>>, Element)>,
-    Cloze,
+    RemainingLength, // start, for error locations
+    Vec<(IsNot<Or<(ClozeStart, Newline)>>, Element)>,
+    ClozeStart,
     Vec<Or<(Cloze, (IsNot<Newline>, Element))>>,
     Option<NoteIdComment>,
     RemainingLength,
@@ -88,6 +105,20 @@ type InlineCode = DelimitedChars<TStr<"`">>;
 // display code
 type MultilineCode = DelimitedChars<TStr<"```">>;
 
+// display code containing at least one cloze, opted into by "cloze" in the info string (```rust cloze)
+const CODE_CLOZE_FLAG: &str = "cloze";
+const CODE_CLOZE_START: &str = "{{";
+const CODE_CLOZE_END: &str = "}}";
+type ClozedCode = (
+    TStr<"```">,
+    Vec<(IsNot<Or<(TStr<CODE_CLOZE_FLAG>, Newline)>>, char)>,
+    TStr<CODE_CLOZE_FLAG>,
+    Vec<(IsNot<Or<(TStr<"```">, TStr<CODE_CLOZE_START>)>>, char)>,
+    TStr<CODE_CLOZE_START>,
+    Vec<(IsNot<TStr<"```">>, char)>,
+    TStr<"```">,
+);
+
 // math
 type Math = Or<(InlineMath, DisplayMath)>;
 // inline math
@@ -95,6 +126,23 @@ type InlineMath = DelimitedChars<TStr<"$">>;
 // display math
 type DisplayMath = DelimitedChars<TStr<"$$">>;
 
+// math containing at least one cloze
+const MATH_CLOZE_DELIM: &str = "==";
+type ClozedMath = Or<(
+    ClozedDelimitedChars<TStr<"$">>,
+    ClozedDelimitedChars<TStr<"$$">>,
+)>;
+// a balanced pair of delimiters, so that comparisons like `$a == b$` aren't clozes
+type ClozedDelimitedChars<Delim> = (
+    Delim,
+    Vec<(IsNot<Or<(Delim, TStr<MATH_CLOZE_DELIM>)>>, char)>,
+    TStr<MATH_CLOZE_DELIM>,
+    Vec<(IsNot<Or<(Delim, TStr<MATH_CLOZE_DELIM>)>>, char)>,
+    TStr<MATH_CLOZE_DELIM>,
+    Vec<(IsNot<Delim>, char)>,
+    Delim,
+);
+
 // Link
 type LinkRenameSeparator = TStr<"|">;
 type Link = (
@@ -122,27 +170,163 @@ type Accent<Delim> = (
 type Italic = Or<(Accent<TStr<"*">>, Accent<TStr<"_">>)>;
 type Bold = Accent<TStr<"**">>;
 
+/// Information about the file being handled, that is needed to convert its contents
+struct FileContext<'a> {
+    path: &'a Path,
+    str: &'a str,
+    /// the math dialect declared for the file, if any
+    math_dialect: Option<MathDialect>,
+}
+impl<'a> FileContext<'a> {
+    fn new(path: &'a Path, str: &'a str) -> Result<Self, HandleMdError> {
+        Ok(Self {
+            path,
+            str,
+            math_dialect: declared_math_dialect(path, str)?,
+        })
+    }
+    /// A link opening the file in obsidian, assuming the current directory is the vault
+    fn obsidian_link(&self) -> String {
+        let vault = PWD
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let file = self
+            .path
+            .strip_prefix(".")
+            .unwrap_or(self.path)
+            .with_extension("");
+        format!(
+            "obsidian://open?vault={}&file={}",
+            percent_encode(&vault),
+            percent_encode(&file.to_string_lossy())
+        )
+    }
+    /// The location of the element with `remaining_length` bytes after its start
+    fn location(&self, remaining_length: Option<usize>) -> Location {
+        Location::new(self.path, self.str, remaining_length)
+    }
+}
+
+/// Percent-encode everything but unreserved characters and '/', for use in an url
+fn percent_encode(str: &str) -> String {
+    let mut encoded = String::with_capacity(str.len());
+    for byte in str.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            byte => write!(encoded, "%{byte:02X}").expect("Writing to string shouldn't fail"),
+        }
+    }
+    encoded
+}
+#[test]
+fn test_percent_encode() {
+    assert_eq!(
+        percent_encode("notes/Über uns.md"),
+        "notes/%C3%9Cber%20uns.md"
+    );
+}
+
+/// A location in a markdown file, for error messages
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub file: PathBuf,
+    /// starting at 1
+    pub line: Option<usize>,
+}
+impl Location {
+    /// The location in the file at `path` with the contents `str`, where `remaining_length` bytes of it are left
+    fn new(path: &Path, str: &str, remaining_length: Option<usize>) -> Self {
+        Location {
+            file: path.to_path_buf(),
+            line: remaining_length.map(|remaining_length| {
+                let index = str.len() - remaining_length;
+                str[..index].matches('\n').count() + 1
+            }),
+        }
+    }
+}
+impl Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.file.display())?;
+        if let Some(line) = self.line {
+            write!(f, ":{line}")?;
+        }
+        Ok(())
+    }
+}
+
 pub struct ClozeData {
     pub contents: String,
+    /// the path of the file, with its parts separated by " > "
+    pub source: String,
+    /// the headings above the cloze, separated by " > "
+    pub breadcrumb: String,
+    /// a link opening the file in obsidian
+    pub obsidian_link: String,
     pub note_id: Option<NoteId>,
     pub pictures: Vec<Picture>,
+    pub location: Location,
     remaining_length: usize,
 }
 
+/// How many notes were left unchanged, updated or added in anki
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SyncStats {
+    pub unchanged: usize,
+    pub updated: usize,
+    pub added: usize,
+    /// existing notes moved to another deck
+    pub moved: usize,
+    /// NoteID comments referring to notes that don't exist in anki
+    pub stale: usize,
+}
+impl AddAssign for SyncStats {
+    fn add_assign(&mut self, other: Self) {
+        self.unchanged += other.unchanged;
+        self.updated += other.updated;
+        self.added += other.added;
+        self.moved += other.moved;
+        self.stale += other.stale;
+    }
+}
+impl Display for SyncStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} notes unchanged, {} updated, {} added, {} moved to another deck",
+            self.unchanged, self.updated, self.added, self.moved
+        )?;
+        if self.stale > 0 {
+            write!(f, ", {} stale NoteIDs", self.stale)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum HandleMdError {
     #[error("Reading/writing file ({file}) failed: {error}")]
     ReadWriteFile { file: PathBuf, error: io::Error },
-    #[error("Failed to lock NOTES: {0}")]
-    Lock(#[from] LockNotesError),
-    #[error("Failed to convert math: {0}")]
-    MathConvert(#[from] MathConvertError),
+    #[error("Failed to convert math at {location}: {error}")]
+    MathConvert {
+        location: Location,
+        error: MathConvertError,
+    },
     #[error("No matching anki deck found for path {0}")]
     DeckLookup(PathBuf),
     #[error("Failed to canonicalize (expand) path {path}: {error}")]
     CanonicalizePath { path: PathBuf, error: io::Error },
 }
-pub fn handle_md(path: &Path) -> Result<(), HandleMdError> {
+/// Syncs the clozes in the file at `path`.
+/// Clozes with a NoteID of a note that doesn't exist in anki are only recreated if `recreate_stale`.
+pub fn handle_md(
+    path: &Path,
+    sync_state: &mut SyncState,
+    recreate_stale: bool,
+) -> Result<SyncStats, HandleMdError> {
     /// the approximate length of a note id comment in bytes.
     /// Right for the years 2001-2286
     const APPROX_LEN_NOTE_ID_COMMENT: usize = "<!--NoteID:0000000000000-->\n".len();
@@ -163,8 +347,9 @@ pub fn handle_md(path: &Path) -> Result<(), HandleMdError> {
         .collect::<String>();
     path_str.truncate(path_str.len() - 3); // remove .md
 
+    let ctx = FileContext::new(path, &str)?;
     let mut tags: Vec<String> = Vec::new();
-    let mut headings: Vec<String> = Vec::new();
+    let mut headings: Vec<HeadingData> = Vec::new();
     let mut clozes: Vec<ClozeData> = Vec::new();
 
     for file_element in parsed.0.0 {
@@ -173,22 +358,28 @@ pub fn handle_md(path: &Path) -> Result<(), HandleMdError> {
             &mut clozes,
             &path_str,
             &mut tags,
+            &ctx,
         ));
         let matcher = AddMatcher::<0>::add_matcher(
             matcher,
-            |cloze_lines, (headings, clozes, path_str, _)| {
-                Ok(handle_cloze_lines(
-                    *cloze_lines,
-                    headings,
-                    clozes,
-                    path_str,
-                )?)
+            |cloze_lines, (headings, clozes, path_str, _, ctx)| {
+                let start = cloze_lines.0.0;
+                handle_cloze_lines(*cloze_lines, headings, clozes, path_str, ctx).map_err(|error| {
+                    HandleMdError::MathConvert {
+                        location: ctx.location(Some(start)),
+                        error,
+                    }
+                })
             },
         );
-        let matcher = AddMatcher::<1>::add_matcher(matcher, |heading, (headings, _, _, _)| {
-            Ok(handle_heading(*heading, headings, &mut Vec::new())?)
+        let matcher = AddMatcher::<1>::add_matcher(matcher, |heading, (headings, _, _, _, ctx)| {
+            let start = heading.0.0;
+            handle_heading(*heading, headings, ctx).map_err(|error| HandleMdError::MathConvert {
+                location: ctx.location(Some(start)),
+                error,
+            })
         });
-        let matcher = AddMatcher::<2>::add_matcher(matcher, |tag, (_, _, _, tags)| {
+        let matcher = AddMatcher::<2>::add_matcher(matcher, |tag, (_, _, _, tags, _)| {
             #[expect(clippy::unit_arg)]
             Ok(tags.push(
                 tag.0
@@ -207,75 +398,285 @@ pub fn handle_md(path: &Path) -> Result<(), HandleMdError> {
         matcher.do_match()?;
     }
 
-    let mut last_read = 0;
-    let mut out_string =
-        String::with_capacity(str.len() + clozes.len() * APPROX_LEN_NOTE_ID_COMMENT);
-    for mut cloze in clozes {
-        let actual_note_id = NOTES
-            .lock()?
-            .iter_mut()
-            .find(|(note, _)| {
-                cloze.note_id.is_some_and(|id| id == note.id)
-                    || note.fields["Text"] == cloze.contents
-            })
-            .map(|(note, seen)| {
-                if *seen {
-                    warn!("Note found more than once: {:?}", note);
-                } else {
-                    *seen = true;
-                }
-                cloze.note_id = Some(note.id);
-                note.id
-            });
+    enum Plan {
+        Unchanged(NoteId),
+        Update(NoteId),
+        Add,
+        /// the note was already synced from another cloze, which is left to own it
+        Duplicate(NoteId),
+        /// an identical cloze earlier in the file (at the index) is added, and shares its note with this one
+        DuplicateOfAdded(usize),
+        /// the cloze isn't synced and keeps its NoteID, which is stale (and the note isn't recreated),
+        /// or refers to a note that can't be synced
+        Skipped,
+    }
 
-        let note_id = cloze.note_id;
-        let index = str.len() - cloze.remaining_length;
+    let canonicalized = path
+        .canonicalize()
+        .map_err(|error| HandleMdError::CanonicalizePath {
+            path: path.to_path_buf(),
+            error,
+        })?;
+    let deck = CONFIG
+        .path_to_deck
+        .iter()
+        .find_map(|mapping| {
+            mapping
+                .path
+                .is_match(&canonicalized.to_string_lossy())
+                .then_some(&mapping.deck)
+        })
+        .or(CONFIG
+            .tag_to_deck
+            .iter()
+            .find_map(|mapping| tags.contains(&mapping.tag).then_some(&mapping.deck)));
+
+    // notes referenced by id, but not owned yet, were created by older versions of anksidian
+    // a NoteID may be repeated in a file, but the note must only be adopted once
+    let unowned: HashSet<NoteId> = clozes
+        .iter()
+        .filter_map(|cloze| cloze.note_id)
+        .filter(|&id| !sync_state.contains(id))
+        .collect();
+    // ids that can't be told to be stale, as the lookup failed or the note has another note type
+    let unsyncable: HashSet<NoteId> = if unowned.is_empty() {
+        HashSet::new()
+    } else {
+        match adopt_notes(unowned.iter().copied().collect(), sync_state) {
+            Ok(foreign) => foreign.into_iter().collect(),
+            Err(error) => {
+                error!("Failed to look up notes of {}: {error}", path.display());
+                unowned
+            }
+        }
+    };
 
-        let final_id = match actual_note_id {
-            // update existing note
-            Some(note_id) => {
-                let result =
-                    update_cloze_note(cloze, tags.iter().map(ToString::to_string).collect());
-                if let Err(e) = result {
-                    error!("{e}");
-                    None
+    // match clozes to existing notes, first by id or text, and then by similarity,
+    // so a cloze can't take the note of another cloze that matches it exactly
+    enum Match {
+        Note(NoteId),
+        Unmatched,
+        Stale,
+        Unsyncable,
+        Duplicate(NoteId),
+    }
+    let mut written_ids = Vec::with_capacity(clozes.len());
+    let mut matches = Vec::with_capacity(clozes.len());
+    let mut stale = 0;
+    for cloze in &mut clozes {
+        written_ids.push(cloze.note_id);
+        if let Some(id) = cloze.note_id
+            && unsyncable.contains(&id)
+        {
+            println!(
+                "NoteID {} at {} refers to a note that isn't of the configured note type, \
+                or couldn't be looked up, skipping it",
+                id.0, cloze.location
+            );
+            matches.push(Match::Unsyncable);
+            continue;
+        }
+        // the note was deleted in anki
+        if let Some(id) = cloze.note_id
+            && !sync_state.contains(id)
+        {
+            stale += 1;
+            if recreate_stale {
+                println!(
+                    "NoteID {} at {} refers to a note not present in Anki, recreating it",
+                    id.0, cloze.location
+                );
+                cloze.note_id = None;
+            } else {
+                println!(
+                    "NoteID {} at {} refers to a note not present in Anki. \
+                    Run with --fix-stale-ids or set recreate_stale_notes to recreate it",
+                    id.0, cloze.location
+                );
+                matches.push(Match::Stale);
+                continue;
+            }
+        }
+        let found = sync_state
+            .find(cloze.note_id, &cloze_text(cloze))
+            .map(|note| note.id);
+        matches.push(match found {
+            // same id or text as a cloze seen before, which is reported later
+            Some(id) if sync_state.is_seen(id) => {
+                sync_state.mark_seen(id, cloze.location.clone());
+                match CONFIG.duplicate_clozes {
+                    DuplicateClozes::Dedupe => Match::Duplicate(id),
+                    DuplicateClozes::Separate => {
+                        cloze.note_id = None;
+                        Match::Unmatched
+                    }
+                }
+            }
+            Some(id) => {
+                sync_state.mark_seen(id, cloze.location.clone());
+                Match::Note(id)
+            }
+            None => Match::Unmatched,
+        });
+    }
+    // clozes that lost their id and were edited slightly
+    for (cloze, cloze_match) in clozes.iter().zip(&mut matches) {
+        if let Match::Unmatched = cloze_match
+            && let Some(id) = sync_state
+                .find_similar(&cloze_text(cloze), CONFIG.relink_threshold, |note| {
+                    note.is_from(&cloze.source)
+                })
+                .map(|note| note.id)
+        {
+            println!(
+                "Relinked cloze at {} to similar note {}",
+                cloze.location, id.0
+            );
+            sync_state.mark_seen(id, cloze.location.clone());
+            *cloze_match = Match::Note(id);
+        }
+    }
+
+    // plan additions and updates, which are then sent together
+    let mut batch = NoteBatch::default();
+    let mut plans = Vec::with_capacity(clozes.len());
+    // text -> index of the cloze it is added from
+    let mut added_texts = HashMap::new();
+    for (index, (cloze, cloze_match)) in clozes.iter_mut().zip(matches).enumerate() {
+        let plan = match cloze_match {
+            Match::Note(id) => {
+                cloze.note_id = Some(id);
+                let note = sync_state
+                    .get(id)
+                    .expect("Matched notes are present in the sync state");
+                // existing note, which already has the current contents
+                if note.is_up_to_date(cloze, &tags) {
+                    Plan::Unchanged(id)
+                // update existing note
                 } else {
-                    Some(note_id)
+                    batch.update_cloze_note(cloze, note, &tags);
+                    Plan::Update(id)
                 }
             }
-            // add new note
-            None => {
-                let canonicalized =
-                    path.canonicalize()
-                        .map_err(|error| HandleMdError::CanonicalizePath {
-                            path: path.to_path_buf(),
-                            error,
-                        })?;
-
-                let deck = CONFIG
-                    .path_to_deck
-                    .iter()
-                    .find_map(|mapping| {
-                        mapping
-                            .path
-                            .is_match(&canonicalized.to_string_lossy())
-                            .then_some(&mapping.deck)
-                    })
-                    .or(CONFIG
-                        .tag_to_deck
-                        .iter()
-                        .find_map(|mapping| tags.contains(&mapping.tag).then_some(&mapping.deck)))
-                    .ok_or_else(|| HandleMdError::DeckLookup(path.to_path_buf()))?;
-
-                match add_cloze_note(cloze, tags.iter().map(ToString::to_string).collect(), deck) {
-                    Ok(note_id) => Some(note_id),
-                    Err(e) => {
-                        error!("{e}");
-                        None
+            Match::Unmatched => {
+                let first = match CONFIG.duplicate_clozes {
+                    DuplicateClozes::Dedupe => {
+                        *added_texts.entry(cloze_text(cloze)).or_insert(index)
                     }
+                    DuplicateClozes::Separate => index,
+                };
+                if first == index {
+                    // add new note
+                    let deck = deck.ok_or_else(|| HandleMdError::DeckLookup(path.to_path_buf()))?;
+                    batch.add_cloze_note(cloze, &tags, deck);
+                    Plan::Add
+                } else {
+                    Plan::DuplicateOfAdded(first)
                 }
             }
+            Match::Stale | Match::Unsyncable => Plan::Skipped,
+            Match::Duplicate(id) => {
+                cloze.note_id = Some(id);
+                Plan::Duplicate(id)
+            }
         };
+        plans.push(plan);
+    }
+    let sent_clozes = clozes
+        .iter()
+        .zip(&plans)
+        .filter(|(_, plan)| matches!(plan, Plan::Update(_) | Plan::Add))
+        .map(|(cloze, _)| cloze);
+    let results: Vec<Option<NoteId>> = batch
+        .send()
+        .into_iter()
+        .zip(sent_clozes)
+        .map(|(result, cloze)| {
+            result
+                .inspect_err(|e| error!("Failed to sync cloze at {}: {e}", cloze.location))
+                .ok()
+        })
+        .collect();
+
+    let mut stats = SyncStats {
+        stale,
+        ..SyncStats::default()
+    };
+    let mut results = results.into_iter();
+    let mut final_ids: Vec<Option<NoteId>> = Vec::with_capacity(plans.len());
+    for (cloze, plan) in clozes.iter().zip(&plans) {
+        let final_id = match *plan {
+            Plan::Unchanged(note_id) | Plan::Duplicate(note_id) => {
+                stats.unchanged += 1;
+                Some(note_id)
+            }
+            Plan::Update(_) => {
+                let note_id = results.next().flatten();
+                stats.updated += usize::from(note_id.is_some());
+                note_id
+            }
+            Plan::Add => {
+                let note_id = results.next().flatten();
+                stats.added += usize::from(note_id.is_some());
+                // so identical clozes in later files are matched to it, and reported as duplicates
+                if let Some(note_id) = note_id {
+                    sync_state.insert(UpdateNote::added(note_id, cloze, &tags), Vec::new());
+                    sync_state.mark_seen(note_id, cloze.location.clone());
+                }
+                note_id
+            }
+            Plan::DuplicateOfAdded(first) => {
+                let note_id = final_ids[first];
+                if let Some(note_id) = note_id {
+                    sync_state.mark_seen(note_id, cloze.location.clone());
+                }
+                note_id
+            }
+            Plan::Skipped => None,
+        };
+        final_ids.push(final_id);
+    }
+
+    // move existing notes, whose deck changed through moving the file, retagging it or editing the config
+    if let Some(deck) = deck {
+        let existing: Vec<(&ClozeData, &[CardId])> = clozes
+            .iter()
+            .zip(&plans)
+            .filter_map(|(cloze, plan)| match *plan {
+                Plan::Unchanged(note_id) | Plan::Update(note_id) => {
+                    Some((cloze, sync_state.cards(note_id)))
+                }
+                Plan::Add | Plan::Skipped | Plan::Duplicate(_) | Plan::DuplicateOfAdded(_) => None,
+            })
+            .collect();
+        let targets: Vec<(&[CardId], &str)> = existing
+            .iter()
+            .map(|(_, cards)| (*cards, deck.as_str()))
+            .collect();
+        match move_to_decks(&targets) {
+            Ok(moves) => {
+                for ((cloze, _), deck_move) in existing.iter().zip(moves) {
+                    if let Some(DeckMove { from, to }) = deck_move {
+                        println!(
+                            "Moved note at {} from deck '{from}' to '{to}'",
+                            cloze.location
+                        );
+                        stats.moved += 1;
+                    }
+                }
+            }
+            Err(e) => error!(
+                "Failed to move notes of {} to deck '{deck}': {e}",
+                path.display()
+            ),
+        }
+    }
+
+    let mut last_read = 0;
+    let mut out_string =
+        String::with_capacity(str.len() + clozes.len() * APPROX_LEN_NOTE_ID_COMMENT);
+    for ((cloze, note_id), final_id) in clozes.iter().zip(written_ids).zip(final_ids) {
+        let index = str.len() - cloze.remaining_length;
 
         out_string.push_str(&str[last_read..index]);
         last_read = index;
@@ -308,19 +709,80 @@ pub fn handle_md(path: &Path) -> Result<(), HandleMdError> {
     fs::write(path, out_string).map_err(|error| HandleMdError::ReadWriteFile {
         file: path.to_path_buf(),
         error,
-    })
+    })?;
+    Ok(stats)
+}
+
+/// Get the math dialect declared in the frontmatter (`math: typst`), or by the config for the path
+fn declared_math_dialect(path: &Path, str: &str) -> Result<Option<MathDialect>, HandleMdError> {
+    if let Some(value) = frontmatter_value(str, "math") {
+        match value.to_lowercase().as_str() {
+            "typst" => return Ok(Some(MathDialect::Typst)),
+            "latex" => return Ok(Some(MathDialect::Latex)),
+            other => warn!(
+                "Unknown math dialect '{other}' in frontmatter of {}, expected typst or latex",
+                path.display()
+            ),
+        }
+    }
+
+    if CONFIG.path_to_math.is_empty() {
+        return Ok(None);
+    }
+    let canonicalized = path
+        .canonicalize()
+        .map_err(|error| HandleMdError::CanonicalizePath {
+            path: path.to_path_buf(),
+            error,
+        })?;
+    Ok(CONFIG.path_to_math.iter().find_map(|mapping| {
+        mapping
+            .path
+            .is_match(&canonicalized.to_string_lossy())
+            .then_some(mapping.math)
+    }))
+}
+
+/// Get the value of `key` in the yaml frontmatter of `str`, only supporting `key: value` lines
+fn frontmatter_value<'a>(str: &'a str, key: &str) -> Option<&'a str> {
+    let mut lines = str.lines();
+    if lines.next()?.trim_end() != "---" {
+        return None;
+    }
+    lines
+        .take_while(|line| line.trim_end() != "---")
+        .find_map(|line| {
+            let (line_key, value) = line.split_once(':')?;
+            (line_key.trim() == key).then(|| value.trim().trim_matches(['"', '\'']))
+        })
+}
+#[test]
+fn test_frontmatter_value() {
+    let str = "---\ntags: [a]\nmath: \"typst\"\n---\nmath: latex";
+    assert_eq!(frontmatter_value(str, "math"), Some("typst"));
+    assert_eq!(frontmatter_value(str, "other"), None);
+    assert_eq!(frontmatter_value("math: latex", "math"), None);
+}
+
+/// A heading above clozes, with the pictures of its rendered math
+#[derive(Default)]
+struct HeadingData {
+    contents: String,
+    pictures: Vec<Picture>,
 }
 
 fn handle_heading(
     heading: Heading,
-    headings: &mut Vec<String>,
-    pictures: &mut Vec<Picture>,
+    headings: &mut Vec<HeadingData>,
+    ctx: &FileContext,
 ) -> Result<(), MathConvertError> {
-    let level = heading.0.0.len();
+    let level = heading.1.0.len();
     let mut contents = String::new();
-    for (_, element) in heading.2 {
-        contents.push_str(&element_to_string(element, pictures)?);
+    let mut pictures = Vec::new();
+    for (_, element) in heading.3 {
+        contents.push_str(&element_to_string(element, ctx, &mut pictures, None)?);
     }
+    let contents = HeadingData { contents, pictures };
 
     match level.cmp(&headings.len()) {
         Ordering::Less => {
@@ -361,6 +823,64 @@ fn code_to_string(code: Code) -> String {
     matcher.do_match()
 }
 
+/// Convert a fenced code block with `{{...}}` clozes to html, keeping its indentation
+fn clozed_code_to_string(code: ClozedCode, cloze_num: &mut u8) -> String {
+    let info = code.1.iter().map(|char| char.1).collect::<String>();
+    let body = code
+        .3
+        .iter()
+        .map(|char| char.1)
+        .chain(CODE_CLOZE_START.chars())
+        .chain(code.5.iter().map(|char| char.1))
+        .collect::<String>();
+    // the info string ends at the first newline
+    let (info_rest, body) = body.split_once('\n').unwrap_or((body.as_str(), ""));
+    let body = body.strip_suffix('\n').unwrap_or(body);
+
+    let mut html = match info.split_whitespace().next() {
+        Some(language) => format!("<pre><code class=\"language-{language}\">"),
+        None => String::from("<pre><code>"),
+    };
+    if !info_rest.trim().is_empty() {
+        warn!("Ignoring '{info_rest}' after '{CODE_CLOZE_FLAG}' in code block info string");
+    }
+
+    let mut rest = body;
+    while let Some((before, after)) = rest.split_once(CODE_CLOZE_START) {
+        let Some((contents, after)) = after.split_once(CODE_CLOZE_END) else {
+            warn!("Unclosed cloze in code block '{body}'");
+            break;
+        };
+        html.push_str(&escape_cloze(&escape_html(before)));
+        *cloze_num += 1;
+        write!(
+            html,
+            "{{{{c{cloze_num}::{}}}}}",
+            escape_cloze(&escape_html(contents))
+        )
+        .expect("Writing to string shouldn't fail");
+        rest = after;
+    }
+    html.push_str(&escape_cloze(&escape_html(rest)));
+    html.push_str("</code></pre>");
+    html
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+#[test]
+fn test_clozed_code_to_string() {
+    let (code, _) = ClozedCode::tparse("```rust cloze\nif a < b {{&& c}} + 1\n```")
+        .expect("Should parse as clozed code");
+    assert_eq!(
+        clozed_code_to_string(code, &mut 0),
+        "<pre><code class=\"language-rust\">if a &lt; b {{c1::&amp;&amp; c}} + 1</code></pre>"
+    );
+}
+
 fn accent_to_string<Delim: TParse>(value: &Accent<Delim>, html_tag: &str) -> String {
     format!(
         "<{html_tag}>{}{}</{html_tag}>",
@@ -375,14 +895,19 @@ fn italic_to_string(italic: Italic) -> String {
     matcher.do_match()
 }
 
+/// `cloze_num` is the number of the last cloze of the surrounding cloze lines, if there are any
 fn element_to_string(
     element: Element,
+    ctx: &FileContext,
     pictures: &mut Vec<Picture>,
+    cloze_num: Option<&mut u8>,
 ) -> Result<String, MathConvertError> {
-    let matcher = element.matcher(pictures);
+    let matcher = element.matcher((pictures, cloze_num));
     let matcher = AddMatcher::<0>::add_matcher(matcher, |code, _| Ok(code_to_string(*code)));
-    let matcher = AddMatcher::<1>::add_matcher(matcher, |math, _| convert_math(*math));
-    let matcher = AddMatcher::<2>::add_matcher(matcher, |link, pictures| {
+    let matcher = AddMatcher::<1>::add_matcher(matcher, |math, (pictures, cloze_num)| {
+        convert_math(*math, ctx, pictures, cloze_num)
+    });
+    let matcher = AddMatcher::<2>::add_matcher(matcher, |link, (pictures, _)| {
         Ok(link_to_string(*link, pictures))
     });
 
@@ -396,84 +921,140 @@ fn element_to_string(
 
 fn handle_cloze_lines(
     cloze_lines: ClozeLines,
-    headings: &[String],
+    headings: &[HeadingData],
     clozes: &mut Vec<ClozeData>,
     path_str: &str,
+    ctx: &FileContext,
 ) -> Result<(), MathConvertError> {
     let mut string = String::new();
     let mut pictures = Vec::new();
-    for (_, element) in cloze_lines.0 {
-        string.push_str(&element_to_string(element, &mut pictures)?);
-    }
-
     let mut cloze_num: u8 = 0;
     let mut note_id = None;
 
+    for (_, element) in cloze_lines.1 {
+        string.push_str(&element_to_string(
+            element,
+            ctx,
+            &mut pictures,
+            Some(&mut cloze_num),
+        )?);
+    }
+
     fn add_cloze(
         cloze: Cloze,
         string: &mut String,
         cloze_num: &mut u8,
+        ctx: &FileContext,
         pictures: &mut Vec<Picture>,
     ) -> Result<(), MathConvertError> {
         *cloze_num += 1;
 
         write!(string, "{{{{c{cloze_num}::").expect("Writing to string shouldn't fail");
         for (_, element) in cloze.1.0 {
-            string.push_str(&element_to_string(element, pictures)?);
+            string.push_str(&element_to_string(
+                element,
+                ctx,
+                pictures,
+                Some(&mut *cloze_num),
+            )?);
         }
         string.push_str("}}");
         Ok(())
     }
-    add_cloze(cloze_lines.1, &mut string, &mut cloze_num, &mut pictures)?;
 
-    for element_or_cloze in cloze_lines.2 {
+    let matcher = cloze_lines
+        .2
+        .matcher((&mut string, &mut pictures, &mut cloze_num));
+    let matcher = AddMatcher::<0>::add_matcher(matcher, |cloze, (string, pictures, cloze_num)| {
+        add_cloze(*cloze, string, cloze_num, ctx, pictures)
+    });
+    let matcher = AddMatcher::<1>::add_matcher(matcher, |math, (string, pictures, cloze_num)| {
+        #[expect(clippy::unit_arg)]
+        Ok(string.push_str(&clozed_math_to_string(*math, ctx, pictures, cloze_num)?))
+    });
+    let matcher = matcher.add_matcher(|code, (string, _, cloze_num)| {
+        #[expect(clippy::unit_arg)]
+        Ok(string.push_str(&clozed_code_to_string(*code, cloze_num)))
+    });
+    matcher.do_match()?;
+
+    for element_or_cloze in cloze_lines.3 {
         let matcher = element_or_cloze.matcher((&mut string, &mut pictures, &mut cloze_num));
         let matcher =
             AddMatcher::<0>::add_matcher(matcher, |cloze, (string, pictures, cloze_num)| {
-                add_cloze(*cloze, string, cloze_num, pictures)
+                add_cloze(*cloze, string, cloze_num, ctx, pictures)
             });
-        let matcher = matcher.add_matcher(|element, (string, pictures, _)| {
+        let matcher = matcher.add_matcher(|element, (string, pictures, cloze_num)| {
             #[expect(clippy::unit_arg)]
-            Ok(string.push_str(&element_to_string(element.1, pictures)?))
+            Ok(string.push_str(&element_to_string(
+                element.1,
+                ctx,
+                pictures,
+                Some(cloze_num),
+            )?))
         });
         matcher.do_match()?;
     }
-    if let Some(note_id_comment) = cloze_lines.3 {
-        note_id = Some(extract_note_id(note_id_comment));
+    // e.g. the clozes of math couldn't be added
+    if cloze_num == 0 {
+        warn!(
+            "No clozes left in the cloze line at {}, not syncing it",
+            ctx.location(Some(cloze_lines.0.0))
+        );
+        return Ok(());
     }
-
-    // append path & headings
-    string.push_str("<br>");
-    string.push_str(path_str);
-    for heading in headings {
-        if !heading.is_empty() {
-            write!(string, " > {heading}").expect("Writing to string shouldn't fail");
-        }
+    if let Some(note_id_comment) = cloze_lines.4 {
+        note_id = Some(extract_note_id(note_id_comment));
     }
 
-    let remaining_length = cloze_lines.4.0;
+    let breadcrumb = headings
+        .iter()
+        .filter(|heading| !heading.contents.is_empty())
+        .map(|heading| heading.contents.as_str())
+        .intersperse(" > ")
+        .collect();
+    // the breadcrumb contains the rendered math of the headings
+    pictures.extend(
+        headings
+            .iter()
+            .flat_map(|heading| heading.pictures.iter().cloned()),
+    );
+    let remaining_length = cloze_lines.5.0;
 
     clozes.push(ClozeData {
         contents: string,
+        source: path_str.to_string(),
+        breadcrumb,
+        obsidian_link: ctx.obsidian_link(),
         note_id,
         remaining_length,
         pictures,
+        location: ctx.location(Some(cloze_lines.0.0)),
     });
     Ok(())
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug)]
 pub struct Picture {
     pub path: PathBuf,
     pub filename: String,
-    fields: String,
+    /// whether to show the picture on the back, instead of it being embedded into the text
+    pub on_back: bool,
 }
 impl Picture {
     pub fn new(path: PathBuf, filename: String) -> Self {
         Self {
             path,
             filename,
-            fields: String::from("Back Extra"), // TODO: maybe support both front and back
+            on_back: true, // TODO: maybe support both front and back
+        }
+    }
+    /// A picture that is already embedded into the text, like rendered math
+    pub fn inline(path: PathBuf, filename: String) -> Self {
+        Self {
+            path,
+            filename,
+            on_back: false,
         }
     }
 }
@@ -507,37 +1088,29 @@ fn maybe_handle_image(path: &Path, pictures: &mut Vec<Picture>) -> Option<()> {
         if path.extension() == Some(OsStr::new(extension)) && path.exists() {
             // convert jxl to jpeg
             let (path, filename) = if extension == "jxl" {
-                let mut out_path = temp_dir().join(path);
-                out_path.set_extension("jpg");
+                let filename = media_name(path, "jpg").ok()?;
+                let out_dir = temp_dir().join("anksidian/media");
+                let _ = create_dir_all(&out_dir);
+                let out_path = out_dir.join(&filename);
 
-                if let Some(parent) = out_path.parent() {
-                    let _ = create_dir_all(parent);
+                // named after the hash of the jxl, so it only has to be converted once
+                if !out_path.exists() {
+                    Command::new("djxl")
+                        .arg(path)
+                        .arg(&out_path)
+                        .stdout(Stdio::null())
+                        .stderr(Stdio::null())
+                        .spawn()
+                        .ok()?
+                        .wait()
+                        .ok()?
+                        .exit_ok()
+                        .ok()?;
                 }
 
-                Command::new("djxl")
-                    .arg(path)
-                    .arg(&out_path)
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null())
-                    .spawn()
-                    .ok()?
-                    .wait()
-                    .ok()?
-                    .exit_ok()
-                    .ok()?;
-
-                let mut filename = path.to_path_buf();
-                filename.set_extension("jpg");
-
-                (
-                    out_path.canonicalize().ok()?,
-                    filename.file_name()?.to_str()?.to_string(),
-                )
+                (out_path.canonicalize().ok()?, filename)
             } else {
-                (
-                    path.canonicalize().ok()?,
-                    path.file_name()?.to_str()?.to_string(),
-                )
+                (path.canonicalize().ok()?, media_name(path, extension).ok()?)
             };
             pictures.push(Picture::new(path, filename));
             return Some(());
@@ -550,32 +1123,373 @@ fn maybe_handle_image(path: &Path, pictures: &mut Vec<Picture>) -> Option<()> {
 pub enum MathConvertError {
     #[error("Checking if math is typst failed: {0}")]
     IsTypst(#[from] IsTypstError),
-    #[error("Converting typst to latex failed: {0}")]
-    TypstToLatex(#[from] TypstToLatexError),
+    #[error("Converting typst '{snippet}' to latex failed: {error}")]
+    TypstToLatex {
+        snippet: String,
+        error: TypstToLatexError,
+    },
+    #[error("Rendering math failed: {0}")]
+    Render(#[from] RenderMathError),
+}
+#[derive(Clone, Copy)]
+enum MathStyle {
+    Inline,
+    Display,
+}
+impl MathStyle {
+    fn typst(self, inner: &str) -> String {
+        match self {
+            Self::Inline => format!("${inner}$"),
+            Self::Display => format!("$ {inner} $"),
+        }
+    }
+    fn latex(self, inner: &str) -> String {
+        match self {
+            Self::Inline => format!("\\({inner}\\)"),
+            Self::Display => format!("\\[{inner}\\]"),
+        }
+    }
+    fn anki_latex(self, inner: &str) -> String {
+        match self {
+            Self::Inline => format!("[$]{inner}[/$]"),
+            Self::Display => format!("[$$]{inner}[/$$]"),
+        }
+    }
 }
-/// Convert from Obsidian latex/typst to anki latex
-fn convert_math(math: Math) -> Result<String, MathConvertError> {
+
+/// Convert from Obsidian latex/typst to anki latex.
+/// If `cloze_num` is given, `==...==` inside the math is turned into clozes
+fn convert_math(
+    math: Math,
+    ctx: &FileContext,
+    pictures: &mut Vec<Picture>,
+    cloze_num: Option<&mut u8>,
+) -> Result<String, MathConvertError> {
     // extract inner math
     fn extract<Delim: TParse>(math: &DelimitedChars<Delim>) -> String {
         math.1.0.iter().map(|char| char.1).collect()
     }
     let matcher = math.matcher(());
     let matcher = AddMatcher::<0>::add_matcher(matcher, |inner: Box<InlineMath>, _| {
-        let inner = extract(&inner);
-        (format!("${inner}$"), format!("\\({inner}\\)"))
+        (extract(&inner), MathStyle::Inline)
     });
-    let matcher = matcher.add_matcher(|inner: Box<DisplayMath>, _| {
-        let inner = extract(&inner);
-        (format!("$ {inner} $"), format!("\\[{inner}\\]"))
+    let matcher =
+        matcher.add_matcher(|inner: Box<DisplayMath>, _| (extract(&inner), MathStyle::Display));
+    let (inner, style) = matcher.do_match();
+
+    match cloze_num {
+        Some(cloze_num) => convert_clozed_math(&inner, style, ctx, pictures, cloze_num),
+        None => convert_math_inner(&inner, style, ctx, pictures),
+    }
+}
+
+fn clozed_math_to_string(
+    math: ClozedMath,
+    ctx: &FileContext,
+    pictures: &mut Vec<Picture>,
+    cloze_num: &mut u8,
+) -> Result<String, MathConvertError> {
+    // extract inner math, including the delimiters of the first cloze
+    fn extract<Delim: TParse>(math: &ClozedDelimitedChars<Delim>) -> String {
+        math.1
+            .iter()
+            .map(|char| char.1)
+            .chain(MATH_CLOZE_DELIM.chars())
+            .chain(math.3.iter().map(|char| char.1))
+            .chain(MATH_CLOZE_DELIM.chars())
+            .chain(math.5.iter().map(|char| char.1))
+            .collect()
+    }
+    let matcher = math.matcher(());
+    let matcher =
+        AddMatcher::<0>::add_matcher(matcher, |inner: Box<ClozedDelimitedChars<TStr<"$">>>, _| {
+            (extract(&inner), MathStyle::Inline)
+        });
+    let matcher = matcher.add_matcher(|inner: Box<ClozedDelimitedChars<TStr<"$$">>>, _| {
+        (extract(&inner), MathStyle::Display)
     });
-    let (typst_style_math, latex_style_math) = matcher.do_match();
+    let (inner, style) = matcher.do_match();
+
+    convert_clozed_math(&inner, style, ctx, pictures, cloze_num)
+}
+#[test]
+fn test_clozed_math() {
+    assert!(ClozedMath::tparse("$E = ==mc^2==$").is_some());
+    assert!(ClozedMath::tparse("$a == b$").is_none());
+    assert!(ClozedMath::tparse("$$a == b$$").is_none());
+}
+
+fn read_preamble(path: Option<&PathBuf>, kind: &str) -> String {
+    path.map_or_default(|path| match fs::read_to_string(path) {
+        Ok(preamble) => preamble,
+        Err(e) => {
+            error!("Failed to read {kind} preamble {}: {e}", path.display());
+            String::new()
+        }
+    })
+}
+/// The configured latex preamble
+static LATEX_PREAMBLE: LazyLock<String> =
+    LazyLock::new(|| read_preamble(CONFIG.latex_preamble.as_ref(), "latex"));
+/// Macros defined in the configured latex preamble
+static LATEX_MACROS: LazyLock<Macros> = LazyLock::new(|| Macros::parse(&LATEX_PREAMBLE));
+/// Definitions from the configured typst preamble, which are prepended to all typst math
+static TYPST_PREAMBLE: LazyLock<String> =
+    LazyLock::new(|| read_preamble(CONFIG.typst_preamble.as_ref(), "typst"));
+
+/// Whether math is typst, using the file's declared dialect if there is one and probing otherwise
+fn math_is_typst(typst_style_math: &str, ctx: &FileContext) -> Result<bool, IsTypstError> {
+    Ok(!CONFIG.disable_typst
+        && match ctx.math_dialect {
+            Some(MathDialect::Typst) => true,
+            Some(MathDialect::Latex) => false,
+            None => is_typst(typst_style_math, &TYPST_PREAMBLE)?,
+        })
+}
+
+/// Convert typst math (including its $ delimiters) to latex, using the configured preamble
+fn convert_typst(typst: &str) -> Result<String, MathConvertError> {
+    typst_to_latex(typst, &TYPST_PREAMBLE).map_err(|error| MathConvertError::TypstToLatex {
+        snippet: typst.to_string(),
+        error,
+    })
+}
+
+/// Convert math without regard for clozes, in the configured output format
+fn convert_math_inner(
+    inner: &str,
+    style: MathStyle,
+    ctx: &FileContext,
+    pictures: &mut Vec<Picture>,
+) -> Result<String, MathConvertError> {
+    let typst_style_math = style.typst(inner);
+    let typst = math_is_typst(&typst_style_math, ctx)?;
+
+    Ok(match CONFIG.math_output {
+        MathOutput::Mathjax => escape_cloze(&if typst {
+            convert_typst(&typst_style_math)?
+        } else {
+            style.latex(&LATEX_MACROS.expand(inner))
+        }),
+        MathOutput::AnkiLatex => {
+            let latex = if typst {
+                strip_latex_delims(&convert_typst(&typst_style_math)?).to_string()
+            } else {
+                LATEX_MACROS.expand(inner)
+            };
+            escape_cloze(&style.anki_latex(&latex))
+        }
+        MathOutput::Svg => {
+            let svg = if typst {
+                render_math::typst_to_svg(&typst_style_math, &TYPST_PREAMBLE)?
+            } else {
+                render_math::latex_to_svg(&style.latex(inner), &LATEX_PREAMBLE)?
+            };
+            let filename = svg
+                .file_name()
+                .expect("Rendered svgs are named after their hash")
+                .to_string_lossy()
+                .to_string();
+            let html = format!("<img class=\"anksidian-math\" src=\"{filename}\">");
+            pictures.push(Picture::inline(svg, filename));
+            html
+        }
+    })
+}
 
-    Ok(if is_typst(&typst_style_math)? && !CONFIG.disable_typst {
-        typst_to_latex(&typst_style_math)?
+/// Convert math containing clozes (`E = ==mc^2==`) to anki latex with clozes inside, which anki supports for MathJax
+fn convert_clozed_math(
+    inner: &str,
+    style: MathStyle,
+    ctx: &FileContext,
+    pictures: &mut Vec<Picture>,
+    cloze_num: &mut u8,
+) -> Result<String, MathConvertError> {
+    // clozes are at the odd indices
+    let segments: Vec<&str> = inner.split(MATH_CLOZE_DELIM).collect();
+    // math without clozes on a cloze line is converted like any other math, in the configured output format
+    if segments.len() == 1 {
+        return convert_math_inner(inner, style, ctx, pictures);
+    }
+    if segments.len() % 2 == 0 {
+        warn!("Unbalanced cloze delimiters in math '{inner}', not adding clozes");
+        return convert_math_inner(inner, style, ctx, pictures);
+    }
+    if CONFIG.math_output != MathOutput::Mathjax {
+        warn!(
+            "Clozes inside math are only supported with mathjax output, not adding clozes to '{inner}'"
+        );
+        return convert_math_inner(&segments.concat(), style, ctx, pictures);
+    }
+
+    let typst_style_math = style.typst(&segments.concat());
+    if math_is_typst(&typst_style_math, ctx)? {
+        // Replace clozes by string placeholders, so the surrounding math can be converted as a whole.
+        // Pandoc turns them into \text{...}
+        let placeholder = |i: usize| format!("ANKSIDIANCLOZE{i}END");
+        let with_placeholders = segments
+            .iter()
+            .enumerate()
+            .map(|(i, segment)| {
+                if i % 2 == 0 {
+                    segment.to_string()
+                } else {
+                    format!("\"{}\"", placeholder(i))
+                }
+            })
+            .collect::<String>();
+        let mut converted = escape_cloze(&convert_typst(&style.typst(&with_placeholders))?);
+
+        if let Some(missing) = (1..segments.len())
+            .step_by(2)
+            .find(|&i| !converted.contains(&placeholder(i)))
+        {
+            warn!(
+                "Cloze '{}' in math '{inner}' got lost during conversion, not adding clozes",
+                segments[missing]
+            );
+            return convert_math_inner(&segments.concat(), style, ctx, pictures);
+        }
+
+        for (i, segment) in segments.iter().enumerate().skip(1).step_by(2) {
+            *cloze_num += 1;
+            let contents = escape_cloze(strip_latex_delims(&convert_typst(
+                &MathStyle::Inline.typst(segment),
+            )?));
+            let cloze = format!("{{{{c{cloze_num}::{contents}}}}}");
+
+            let placeholder = placeholder(i);
+            let text_placeholder = format!("\\text{{{placeholder}}}");
+            converted = if converted.contains(&text_placeholder) {
+                converted.replace(&text_placeholder, &cloze)
+            } else {
+                converted.replace(&placeholder, &cloze)
+            };
+        }
+        Ok(converted)
     } else {
-        latex_style_math
+        let mut latex = String::new();
+        for (i, segment) in segments.iter().enumerate() {
+            let segment = LATEX_MACROS.expand(segment);
+            if i % 2 == 0 {
+                latex.push_str(&escape_cloze(&segment));
+            } else {
+                *cloze_num += 1;
+                write!(latex, "{{{{c{cloze_num}::{}}}}}", escape_cloze(&segment))
+                    .expect("Writing to string shouldn't fail");
+            }
+        }
+        Ok(style.latex(&latex))
     }
-    .replace("}", "} ")) // avoid confusing anki with }}
+}
+
+/// Strip the `\( \)` or `\[ \]` delimiters from converted latex
+fn strip_latex_delims(latex: &str) -> &str {
+    let trimmed = latex.trim();
+    [("\\(", "\\)"), ("\\[", "\\]")]
+        .into_iter()
+        .find_map(|(start, end)| trimmed.strip_prefix(start)?.strip_suffix(end))
+        .unwrap_or(trimmed)
+}
+
+/// Escape `}}` and `::` (and a trailing `}`) as html entities, so anki doesn't confuse them with cloze syntax
+fn escape_cloze(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut previous = None;
+    let mut chars = text.chars().peekable();
+    while let Some(char) = chars.next() {
+        let is_last = chars.peek().is_none();
+        match char {
+            '}' | ':' if previous == Some(char) => {
+                write!(escaped, "&#{};", char as u32).expect("Writing to string shouldn't fail")
+            }
+            '}' if is_last => escaped.push_str("&#125;"),
+            _ => escaped.push(char),
+        }
+        previous = Some(char);
+    }
+    escaped
+}
+#[test]
+fn test_convert_clozed_math_without_clozes() {
+    let ctx = FileContext {
+        path: Path::new("test.md"),
+        str: "",
+        math_dialect: Some(MathDialect::Latex),
+    };
+    let (mut clozed_pictures, mut pictures) = (Vec::new(), Vec::new());
+    let mut cloze_num = 0;
+    assert_eq!(
+        convert_clozed_math(
+            "x^{2}",
+            MathStyle::Inline,
+            &ctx,
+            &mut clozed_pictures,
+            &mut cloze_num
+        )
+        .expect("Math should convert"),
+        convert_math_inner("x^{2}", MathStyle::Inline, &ctx, &mut pictures)
+            .expect("Math should convert"),
+    );
+    assert_eq!(clozed_pictures.len(), pictures.len());
+    assert_eq!(cloze_num, 0);
+}
+
+#[test]
+fn test_escape_cloze() {
+    assert_eq!(escape_cloze("\\frac{a}{b}"), "\\frac{a}{b&#125;");
+    assert_eq!(escape_cloze("x^{a^{b}} + 1"), "x^{a^{b}&#125; + 1");
+    assert_eq!(escape_cloze("a::b"), "a:&#58;b");
+}
+
+/// Caches whether math is typst and its conversion to latex, as spawning typst and pandoc for every snippet is slow
+#[derive(Serialize, Deserialize, Default)]
+struct MathCache {
+    /// key -> whether the math is typst
+    is_typst: HashMap<String, bool>,
+    /// key -> converted latex
+    latex: HashMap<String, String>,
+}
+impl Cache for MathCache {
+    const FILE_NAME: &'static str = "math_cache.json";
+}
+static MATH_CACHE: LazyLock<Mutex<MathCache>> = LazyLock::new(|| {
+    Mutex::new(MathCache::load().unwrap_or_else(|e| {
+        error!("Failed to load math cache, continuing without it: {e}");
+        MathCache::default()
+    }))
+});
+/// The first lines of `typst --version` and `pandoc --version`, as their output may change between versions
+static TOOL_VERSIONS: LazyLock<String> = LazyLock::new(|| {
+    ["typst", "pandoc"]
+        .into_iter()
+        .map(|program| {
+            Command::new(program)
+                .arg("--version")
+                .output()
+                .ok()
+                .and_then(|output| String::from_utf8(output.stdout).ok())
+                .and_then(|version| version.lines().next().map(ToString::to_string))
+                .unwrap_or_default()
+        })
+        .intersperse("\n".to_string())
+        .collect()
+});
+/// The cache key for `math` with `preamble`, which includes the tool versions
+fn math_cache_key(math: &str, preamble: &str) -> String {
+    let mut hasher = Hasher::new();
+    for part in [TOOL_VERSIONS.as_str(), preamble, math] {
+        hasher.update(part.as_bytes());
+        // separator, so that moving text between parts changes the hash
+        hasher.update(&[0]);
+    }
+    hasher.finalize().to_hex().to_string()
+}
+pub fn save_math_cache() -> Result<(), CacheSaveError> {
+    MATH_CACHE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .save()
 }
 
 #[derive(Error, Debug)]
@@ -587,7 +1501,28 @@ pub enum IsTypstError {
     #[error("Failed to wait for typst process: {0}")]
     Wait(std::io::Error),
 }
-fn is_typst(math: &str) -> Result<bool, IsTypstError> {
+/// `preamble` is prepended to `math`, to make its definitions available
+fn is_typst(math: &str, preamble: &str) -> Result<bool, IsTypstError> {
+    let key = math_cache_key(math, preamble);
+    if let Some(&is_typst) = MATH_CACHE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .is_typst
+        .get(&key)
+    {
+        return Ok(is_typst);
+    }
+
+    let is_typst = probe_typst(math, preamble)?;
+    MATH_CACHE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .is_typst
+        .insert(key, is_typst);
+    Ok(is_typst)
+}
+/// Check if `math` is typst by compiling it
+fn probe_typst(math: &str, preamble: &str) -> Result<bool, IsTypstError> {
     // spawn typst compiler
     let mut child = Command::new("typst")
         .args(["c", "-", "-f", "pdf", "/dev/null"])
@@ -597,12 +1532,12 @@ fn is_typst(math: &str) -> Result<bool, IsTypstError> {
         .spawn()
         .map_err(IsTypstError::Spawn)?;
 
-    // write math to stdin
+    // write preamble & math to stdin
     child
         .stdin
         .take()
         .expect("stdin is piped")
-        .write_all(math.as_bytes())
+        .write_all(format!("{preamble}\n{math}").as_bytes())
         .map_err(IsTypstError::StdinWrite)?;
 
     // success -> true
@@ -617,12 +1552,41 @@ pub enum TypstToLatexError {
     StdinWrite(std::io::Error),
     #[error("Failed to wait for pandoc process: {0}")]
     Wait(std::io::Error),
-    #[error("Pandoc failed: {0}")]
-    ErrExit(#[from] ExitStatusError),
+    #[error("Pandoc failed ({status}): {stderr}")]
+    ErrExit { status: ExitStatus, stderr: String },
     #[error("Pandoc output not utf8: {0}")]
     Utf8(#[from] FromUtf8Error),
 }
-fn typst_to_latex(typst: &str) -> Result<String, TypstToLatexError> {
+/// `preamble` is prepended to `typst`, to make its definitions available.
+/// Uses the builtin converter if possible, and pandoc otherwise
+fn typst_to_latex(typst: &str, preamble: &str) -> Result<String, TypstToLatexError> {
+    // the builtin converter doesn't know the definitions of the preamble
+    if preamble.trim().is_empty() {
+        match typst_math::typst_to_latex(typst) {
+            Ok(latex) => return Ok(latex),
+            Err(e) => debug!("Builtin typst conversion of '{typst}' failed, using pandoc: {e}"),
+        }
+    }
+
+    let key = math_cache_key(typst, preamble);
+    if let Some(latex) = MATH_CACHE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .latex
+        .get(&key)
+    {
+        return Ok(latex.clone());
+    }
+
+    let latex = pandoc_typst_to_latex(typst, preamble)?;
+    MATH_CACHE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .latex
+        .insert(key, latex.clone());
+    Ok(latex)
+}
+fn pandoc_typst_to_latex(typst: &str, preamble: &str) -> Result<String, TypstToLatexError> {
     let mut child = Command::new("pandoc")
         .args(["-f", "typst", "-t", "latex"])
         .stdin(Stdio::piped())
@@ -635,29 +1599,35 @@ fn typst_to_latex(typst: &str) -> Result<String, TypstToLatexError> {
         .stdin
         .take()
         .expect("stdin is piped")
-        .write_all(typst.as_bytes())
+        .write_all(format!("{preamble}\n{typst}").as_bytes())
         .map_err(TypstToLatexError::StdinWrite)?;
 
-    let mut stdout = child
-        .wait_with_output()
-        .map_err(TypstToLatexError::Wait)?
-        .exit_ok()?
-        .stdout;
-    // remove trailing newline
-    stdout.truncate(stdout.len() - 1);
+    let output = child.wait_with_output().map_err(TypstToLatexError::Wait)?;
+    if !output.status.success() {
+        return Err(TypstToLatexError::ErrExit {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
 
-    String::from_utf8(stdout).map_err(TypstToLatexError::Utf8)
+    // remove surrounding newlines, which the preamble may have added to
+    Ok(String::from_utf8(output.stdout)
+        .map_err(TypstToLatexError::Utf8)?
+        .trim()
+        .to_string())
 }
 
 #[derive(Error, Debug)]
 pub enum MarkNotesAsSeenError {
     #[error("Reading file ({file}) failed: {error}")]
     ReadFile { file: PathBuf, error: io::Error },
-    #[error("Failed to lock NOTES: {0}")]
-    Lock(#[from] LockNotesError),
 }
-/// Parse the file and mark any notes contained as seen in `NOTES`
-pub fn mark_notes_as_seen(file: &Path) -> Result<(), MarkNotesAsSeenError> {
+/// Marks the notes of the NoteIDs in `file` as seen, returning false instead if the file needs to be handled,
+/// because some of them aren't present in anki or are duplicates that should get separate notes
+pub fn mark_notes_as_seen(
+    file: &Path,
+    sync_state: &mut SyncState,
+) -> Result<bool, MarkNotesAsSeenError> {
     let str = fs::read_to_string(file).map_err(|error| MarkNotesAsSeenError::ReadFile {
         file: file.to_path_buf(),
         error,
@@ -666,36 +1636,20 @@ pub fn mark_notes_as_seen(file: &Path) -> Result<(), MarkNotesAsSeenError> {
     let parsed = File::tparse(&str)
         .expect("Parsing file can't fail, as it includes a Vec<char> option, that always matches");
 
+    let mut note_ids = Vec::new();
     for file_element in parsed.0.0 {
-        let matcher: Matcher<_, _, _, _> =
-            file_element.matcher::<_, Result<(), MarkNotesAsSeenError>>(());
-        let matcher = AddMatcher::<0>::add_matcher(matcher, |cloze_lines, _| {
-            if let Some(note_id) = cloze_lines.3 {
-                let note_id = extract_note_id(note_id);
-                let mut lock = NOTES.lock()?;
-                let note = lock.iter_mut().find(|(note, _)| note.id == note_id);
-
-                match note {
-                    Some((note, seen)) => {
-                        if *seen {
-                            warn!("Note found more than once: {:?}", note);
-                        } else {
-                            *seen = true;
-                        }
-                    }
-                    None => {
-                        warn!(
-                            "Note not present in Anki found in unchanged file, not updating.\nTo update, modify the file or remove the file cache at {}",
-                            FileCache::get_path().map_or(
-                                String::from("~/.cache/anksidian/file_cache.json"),
-                                |path| path.to_string_lossy().to_string()
-                            )
-                        )
-                    }
+        let matcher: Matcher<_, _, _, _> = file_element
+            .matcher::<_, Result<(), MarkNotesAsSeenError>>((&mut note_ids, file, str.as_str()));
+        let matcher =
+            AddMatcher::<0>::add_matcher(matcher, |cloze_lines, (note_ids, file, str)| {
+                if let Some(note_id) = cloze_lines.4 {
+                    note_ids.push((
+                        extract_note_id(note_id),
+                        Location::new(file, str, Some(cloze_lines.0.0)),
+                    ));
                 }
-            }
-            Ok(())
-        });
+                Ok(())
+            });
         let matcher = AddMatcher::<1>::add_matcher(matcher, |_, _| Ok(()));
         let matcher = AddMatcher::<2>::add_matcher(matcher, |_, _| Ok(()));
         let matcher = AddMatcher::<3>::add_matcher(matcher, |_, _| Ok(()));
@@ -707,7 +1661,19 @@ pub fn mark_notes_as_seen(file: &Path) -> Result<(), MarkNotesAsSeenError> {
         matcher.do_match()?;
     }
 
-    Ok(())
+    let mut in_file = HashSet::new();
+    let needs_handling = note_ids.iter().any(|(id, _)| {
+        !sync_state.contains(*id)
+            || (CONFIG.duplicate_clozes == DuplicateClozes::Separate
+                && (sync_state.is_seen(*id) || !in_file.insert(*id)))
+    });
+    if needs_handling {
+        return Ok(false);
+    }
+    for (id, location) in note_ids {
+        sync_state.mark_seen(id, location);
+    }
+    Ok(true)
 }
 
 fn extract_note_id(note_id_comment: NoteIdComment) -> NoteId {
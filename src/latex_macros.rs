@@ -0,0 +1,250 @@
+use log::warn;
+use std::collections::HashMap;
+
+// Expands user-defined latex macros (\newcommand & co.), as anki's MathJax doesn't know
+// about the ones defined in e.g. an Obsidian MathJax preamble.
+
+/// How deep macros may expand into other macros, to guard against recursive definitions
+const MAX_EXPANSION_DEPTH: u8 = 32;
+
+#[derive(Debug)]
+struct Macro {
+    args: usize,
+    /// default value of the first argument, which makes it optional
+    default_arg: Option<String>,
+    body: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Macros(HashMap<String, Macro>);
+impl Macros {
+    /// Parse the macro definitions in `preamble`.
+    /// Supports \newcommand, \renewcommand, \providecommand, \def (without parameters) and \DeclareMathOperator
+    pub fn parse(preamble: &str) -> Self {
+        let mut macros = HashMap::new();
+        let mut rest = preamble;
+        while let Some(start) = rest.find('\\') {
+            let (command, after) =
+                control_sequence(&rest[start..]).expect("Starts with a backslash");
+            rest = after;
+            match command {
+                "\\newcommand" | "\\renewcommand" | "\\providecommand" => {
+                    let rest_without_star = rest.strip_prefix('*').unwrap_or(rest);
+                    let (name, after) = argument(rest_without_star);
+                    let (args, after) = optional_argument(after);
+                    let (default_arg, after) = optional_argument(after);
+                    let (body, after) = argument(after);
+                    rest = after;
+
+                    let args = match args.map(|args| args.trim().parse()) {
+                        None => 0,
+                        Some(Ok(args)) => args,
+                        Some(Err(_)) => {
+                            warn!("Invalid argument count for latex macro {name}, skipping it");
+                            continue;
+                        }
+                    };
+                    macros.insert(
+                        name.trim().to_string(),
+                        Macro {
+                            args,
+                            default_arg: default_arg.map(ToString::to_string),
+                            body: body.to_string(),
+                        },
+                    );
+                }
+                "\\def" => {
+                    let Some((name, after)) = control_sequence(rest.trim_start()) else {
+                        warn!("Expected a control sequence after \\def, skipping it");
+                        continue;
+                    };
+                    if !after.starts_with('{') {
+                        warn!("Only \\def without parameters is supported, skipping {name}");
+                        continue;
+                    }
+                    let (body, after) = argument(after);
+                    rest = after;
+                    macros.insert(
+                        name.to_string(),
+                        Macro {
+                            args: 0,
+                            default_arg: None,
+                            body: body.to_string(),
+                        },
+                    );
+                }
+                "\\DeclareMathOperator" => {
+                    let (star, rest_without_star) = match rest.strip_prefix('*') {
+                        Some(without_star) => ("*", without_star),
+                        None => ("", rest),
+                    };
+                    let (name, after) = argument(rest_without_star);
+                    let (operator, after) = argument(after);
+                    rest = after;
+                    macros.insert(
+                        name.trim().to_string(),
+                        Macro {
+                            args: 0,
+                            default_arg: None,
+                            body: format!("\\operatorname{star}{{{operator}}}"),
+                        },
+                    );
+                }
+                _ => {}
+            }
+        }
+        Self(macros)
+    }
+
+    /// Expand all known macros in `latex`
+    pub fn expand(&self, latex: &str) -> String {
+        self.expand_with_depth(latex, 0)
+    }
+    fn expand_with_depth(&self, latex: &str, depth: u8) -> String {
+        let mut expanded = String::with_capacity(latex.len());
+        let mut rest = latex;
+        while let Some(start) = rest.find('\\') {
+            expanded.push_str(&rest[..start]);
+            let (command, after) =
+                control_sequence(&rest[start..]).expect("Starts with a backslash");
+            rest = after;
+
+            let Some(r#macro) = self.0.get(command) else {
+                expanded.push_str(command);
+                continue;
+            };
+            if depth >= MAX_EXPANSION_DEPTH {
+                warn!("Latex macro {command} expands too deeply, it is probably recursive");
+                expanded.push_str(command);
+                continue;
+            }
+
+            let mut args = Vec::with_capacity(r#macro.args);
+            if let Some(default_arg) = &r#macro.default_arg {
+                let (arg, after) = optional_argument(rest);
+                args.push(arg.unwrap_or(default_arg));
+                rest = after;
+            }
+            while args.len() < r#macro.args {
+                let (arg, after) = argument(rest);
+                args.push(arg);
+                rest = after;
+            }
+
+            expanded
+                .push_str(&self.expand_with_depth(&substitute(&r#macro.body, &args), depth + 1));
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+}
+
+/// Replace #1..#9 in `body` with `args`
+fn substitute(body: &str, args: &[&str]) -> String {
+    let mut substituted = String::with_capacity(body.len());
+    let mut chars = body.chars().peekable();
+    while let Some(char) = chars.next() {
+        if char != '#' {
+            substituted.push(char);
+            continue;
+        }
+        match chars.peek().copied() {
+            Some('#') => {
+                chars.next();
+                substituted.push('#');
+            }
+            Some(digit @ '1'..='9') => {
+                chars.next();
+                let index = digit as usize - '1' as usize;
+                substituted.push_str(args.get(index).copied().unwrap_or_default());
+            }
+            _ => substituted.push('#'),
+        }
+    }
+    substituted
+}
+
+/// Split off the control sequence at the start of `latex` (`\name` or a single escaped character),
+/// or None if it doesn't start with one
+fn control_sequence(latex: &str) -> Option<(&str, &str)> {
+    let name = latex.strip_prefix('\\')?;
+    let name_len = name
+        .char_indices()
+        .find(|(_, char)| !char.is_ascii_alphabetic())
+        .map_or(name.len(), |(index, _)| index);
+    // control symbols like \, or \{
+    let name_len = match name_len {
+        0 => name.chars().next().map_or(0, char::len_utf8),
+        len => len,
+    };
+    Some(latex.split_at(1 + name_len))
+}
+
+/// Split off a (brace-delimited, control sequence or single character) argument, skipping leading whitespace
+fn argument(latex: &str) -> (&str, &str) {
+    let latex = latex.trim_start();
+    match latex.chars().next() {
+        Some('{') => delimited(latex, '{', '}'),
+        Some('\\') => control_sequence(latex).expect("Starts with a backslash"),
+        Some(char) => latex.split_at(char.len_utf8()),
+        None => ("", latex),
+    }
+}
+
+/// Split off an optional argument in brackets
+fn optional_argument(latex: &str) -> (Option<&str>, &str) {
+    let trimmed = latex.trim_start();
+    if trimmed.starts_with('[') {
+        let (arg, rest) = delimited(trimmed, '[', ']');
+        (Some(arg), rest)
+    } else {
+        (None, latex)
+    }
+}
+
+/// Split off a balanced group starting with `open`, returning its contents without the delimiters
+fn delimited(latex: &str, open: char, close: char) -> (&str, &str) {
+    let mut depth = 0usize;
+    let mut escaped = false;
+    for (i, char) in latex.char_indices() {
+        match char {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            char if char == open => depth += 1,
+            char if char == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return (&latex[1..i], &latex[i + 1..]);
+                }
+            }
+            _ => {}
+        }
+    }
+    warn!("Unbalanced '{open}' in latex '{latex}'");
+    (&latex[1..], "")
+}
+
+#[test]
+fn test_expand_macros() {
+    let macros = Macros::parse(
+        "\\newcommand{\\R}{\\mathbb{R}}\n\
+        \\newcommand\\abs[1]{\\left|#1\\right|}\n\
+        \\newcommand{\\norm}[2][2]{\\|#2\\|_{#1}}\n\
+        \\def\\eps{\\varepsilon}\n\
+        \\DeclareMathOperator{\\tr}{tr}",
+    );
+    assert_eq!(
+        macros.expand("x \\in \\R, \\abs{\\eps} < \\tr A"),
+        "x \\in \\mathbb{R}, \\left|\\varepsilon\\right| < \\operatorname{tr} A"
+    );
+    assert_eq!(
+        macros.expand("\\norm{v} + \\norm[1]{w}"),
+        "\\|v\\|_{2} + \\|w\\|_{1}"
+    );
+    assert_eq!(macros.expand("\\Rightarrow"), "\\Rightarrow");
+}
+#[test]
+fn test_malformed_preamble() {
+    let macros = Macros::parse("\\def\\é{x} \\def é{y} \\newcommand{\\a}{b} \\def");
+    assert_eq!(macros.expand("\\a \\é"), "b x");
+}
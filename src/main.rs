@@ -14,31 +14,65 @@ use std::{
     collections::HashMap,
     env,
     fmt::Display,
-    fs::{self, File, OpenOptions},
-    io::{self, BufWriter, Read},
+    fs::{self, File},
+    io::{self, Read},
     ops::Not,
     path::{Path, PathBuf},
     process::exit,
     sync::LazyLock,
+    time::Duration,
 };
 use thiserror::Error;
 use ureq::Agent;
 
 use crate::{
-    anki::{handle_unseen_notes, initialize_notes},
-    handle_md::{HandleMdError, MarkNotesAsSeenError, handle_md, mark_notes_as_seen},
+    anki::{
+        OrphanPolicy, check_connection, ensure_deck_exists, ensure_model_exists,
+        handle_unseen_notes, initialize_notes, revive_orphans, stored_media_names,
+    },
+    cache::Cache,
+    handle_md::{HandleMdError, MarkNotesAsSeenError, SyncStats, handle_md, mark_notes_as_seen},
+    sync_state::SyncState,
 };
 
 mod anki;
+mod cache;
 mod handle_md;
+mod latex_macros;
+mod media;
+mod render_math;
+mod similarity;
+mod sync_state;
+mod typst_math;
 
 #[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
 struct Config {
     path_to_deck: Vec<PathToDeck>,
     tag_to_deck: Vec<TagToDeck>,
     #[serde(with = "serde_regex")]
     ignore_paths: Vec<Regex>,
     disable_typst: bool,
+    /// math dialects of paths, so they don't have to be detected
+    path_to_math: Vec<PathToMath>,
+    /// file with latex macro definitions (\newcommand etc.), that are expanded before sending math to anki
+    latex_preamble: Option<PathBuf>,
+    /// file with typst definitions (#let etc.), that are available to all typst math
+    typst_preamble: Option<PathBuf>,
+    math_output: MathOutput,
+    anki_connect: AnkiConnectConfig,
+    cloze_note_type: NoteType,
+    /// create and use the "Anksidian Cloze" note type, instead of `cloze_note_type`
+    provision_note_type: bool,
+    /// tag of all notes created by anksidian, which are the only notes it touches
+    ownership_tag: String,
+    /// recreate the notes of NoteIDs that refer to notes deleted in anki, instead of only reporting them
+    recreate_stale_notes: bool,
+    /// how similar (0 to 1) a cloze without a matching note has to be to an unseen note from the same file,
+    /// to be relinked to it instead of being added as a new note
+    relink_threshold: f64,
+    /// what to do with identical clozes (or ones with the same NoteID) in several places
+    duplicate_clozes: DuplicateClozes,
 }
 impl Default for Config {
     fn default() -> Self {
@@ -49,7 +83,18 @@ impl Default for Config {
             }],
             ignore_paths: vec![Regex::new(".*Excalidraw").expect("Should be a valid regex")],
             disable_typst: false,
+            path_to_math: vec![],
             tag_to_deck: vec![],
+            latex_preamble: None,
+            typst_preamble: None,
+            math_output: MathOutput::default(),
+            anki_connect: AnkiConnectConfig::default(),
+            cloze_note_type: NoteType::default(),
+            provision_note_type: false,
+            ownership_tag: "anksidian".to_string(),
+            recreate_stale_notes: false,
+            relink_threshold: 0.8,
+            duplicate_clozes: DuplicateClozes::default(),
         }
     }
 }
@@ -58,6 +103,71 @@ fn test_default_config() {
     Config::default();
 }
 
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+struct AnkiConnectConfig {
+    /// overridden by ANKSIDIAN_ANKI_CONNECT_URL
+    url: String,
+    /// overridden by ANKSIDIAN_ANKI_CONNECT_KEY
+    api_key: Option<String>,
+    timeout_secs: u64,
+    /// how often a failed request is retried, with exponential backoff
+    max_retries: u8,
+    /// the backoff before the first retry
+    initial_backoff_ms: u64,
+}
+impl Default for AnkiConnectConfig {
+    fn default() -> Self {
+        AnkiConnectConfig {
+            url: "http://localhost:8765".to_string(),
+            api_key: None,
+            timeout_secs: 30,
+            max_retries: 5,
+            initial_backoff_ms: 100,
+        }
+    }
+}
+
+/// An anki note type, and which of its fields anksidian fills
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+struct NoteType {
+    model: String,
+    text_field: String,
+    extra_field: String,
+    /// if not set, the source file is appended to the text
+    source_field: Option<String>,
+    /// if not set, the headings above the note are appended to the text
+    breadcrumb_field: Option<String>,
+    /// for a link that opens the source file in obsidian
+    link_field: Option<String>,
+}
+impl Default for NoteType {
+    fn default() -> Self {
+        NoteType {
+            model: "Cloze".to_string(),
+            text_field: "Text".to_string(),
+            extra_field: "Back Extra".to_string(),
+            source_field: None,
+            breadcrumb_field: None,
+            link_field: None,
+        }
+    }
+}
+impl NoteType {
+    /// The note type created by anksidian, if `provision_note_type` is set
+    fn anksidian() -> Self {
+        NoteType {
+            model: anki::ANKSIDIAN_MODEL.to_string(),
+            text_field: "Text".to_string(),
+            extra_field: "Back Extra".to_string(),
+            source_field: Some("Source".to_string()),
+            breadcrumb_field: Some("Breadcrumb".to_string()),
+            link_field: Some("ObsidianLink".to_string()),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 struct PathToDeck {
     #[serde(with = "serde_regex")]
@@ -71,12 +181,52 @@ struct TagToDeck {
     deck: String,
 }
 
+#[derive(Deserialize, Serialize, Clone)]
+struct PathToMath {
+    #[serde(with = "serde_regex")]
+    path: Regex,
+    math: MathDialect,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum MathDialect {
+    Typst,
+    Latex,
+}
+
+/// How math is shown on cards
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum MathOutput {
+    /// latex, rendered by anki's MathJax
+    #[default]
+    Mathjax,
+    /// svg images, rendered locally
+    Svg,
+    /// anki's own latex tags ([$]...[/$]), rendered by anki's local latex installation
+    AnkiLatex,
+}
+
+/// What to do with identical clozes (or ones with the same NoteID) in several places
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum DuplicateClozes {
+    /// sync all of them to one note, which is only updated from the first one
+    #[default]
+    Dedupe,
+    /// give each of them its own note
+    Separate,
+}
+
 static CONFIG: LazyLock<Config> = LazyLock::new(|| {
     let path = dirs::config_dir()
         .expect("Failed to get config directory")
         .join("anksidian/config.json");
 
-    let config = if !fs::exists(&path).expect("Failed to check if folder to deck config exists") {
+    let mut config: Config = if !fs::exists(&path)
+        .expect("Failed to check if folder to deck config exists")
+    {
         if let Err(err) = fs::create_dir_all(
             path.parent()
                 .expect("Path always has a parent, as we join a multi-part path onto it."),
@@ -99,19 +249,24 @@ static CONFIG: LazyLock<Config> = LazyLock::new(|| {
         serde_json::from_str(&string).expect("Failed to deserialize folder to deck config")
     };
 
-    // ensure all decks mentioned in config exist
-    for deck in config
-        .path_to_deck
-        .iter()
-        .map(|mapping| &mapping.deck)
-        .chain(config.tag_to_deck.iter().map(|mapping| &mapping.deck))
-    {
-        anki::ensure_deck_exists(deck).expect("Failed to ensure that deck exists")
+    if config.provision_note_type {
+        config.cloze_note_type = NoteType::anksidian();
+    }
+    if let Ok(url) = env::var("ANKSIDIAN_ANKI_CONNECT_URL") {
+        config.anki_connect.url = url;
+    }
+    if let Ok(key) = env::var("ANKSIDIAN_ANKI_CONNECT_KEY") {
+        config.anki_connect.api_key = Some(key);
     }
 
     config
 });
-static AGENT: LazyLock<Agent> = LazyLock::new(Agent::new_with_defaults);
+static AGENT: LazyLock<Agent> = LazyLock::new(|| {
+    Agent::config_builder()
+        .timeout_global(Some(Duration::from_secs(CONFIG.anki_connect.timeout_secs)))
+        .build()
+        .into()
+});
 static PWD: LazyLock<PathBuf> =
     LazyLock::new(|| env::current_dir().expect("Failed to get current working directory"));
 
@@ -133,24 +288,79 @@ fn main() {
         println!(
             "Options:\n\
             --track-seen: track whether notes were seen in unchanged files\n\
-            --no-cache: do not use the file cache {}\n\n\
+            --no-cache: do not use the file cache {}\n\
+            --clean-media: delete media files uploaded by anksidian that no note references anymore, \
+            instead of syncing\n\
+            --fix-stale-ids: recreate the notes of NoteIDs referring to notes deleted in Anki, \
+            including in unchanged files\n\
+            --orphans=ask|delete|keep|tag|suspend: what to do with notes deleted in your files, \
+            but not from Anki (default: ask)\n\n\
             Both `--track-seen` and `--no-cache` enable anksidian to detect when a note was deleted \
-            in your files, but not from Anki, and will ask to do so for you.",
+            in your files, but not from Anki, and will handle it according to `--orphans`.",
             FileCache::get_path()
                 .map_or_default(|path| format!("(located at {})", path.to_string_lossy()))
         );
         return;
     }
 
-    exit_on_err(initialize_notes(), "Failed to initialize notes");
+    let orphan_policy: OrphanPolicy = env::args()
+        .skip(1)
+        .find_map(|arg| arg.strip_prefix("--orphans=").map(str::to_string))
+        .map(|policy| exit_on_err(policy.parse(), "Invalid --orphans"))
+        .unwrap_or_default();
+
+    exit_on_err(check_connection(), "Failed to connect to AnkiConnect");
+    if CONFIG.provision_note_type {
+        exit_on_err(
+            ensure_model_exists(),
+            "Failed to create or update the anksidian note type",
+        );
+    }
+    // ensure all decks mentioned in config exist
+    for deck in CONFIG
+        .path_to_deck
+        .iter()
+        .map(|mapping| &mapping.deck)
+        .chain(CONFIG.tag_to_deck.iter().map(|mapping| &mapping.deck))
+    {
+        exit_on_err(
+            ensure_deck_exists(deck),
+            "Failed to ensure that deck exists",
+        );
+    }
+    // media files may have been deleted in anki since they were uploaded
+    match stored_media_names() {
+        Ok(names) => media::retain_stored(&names),
+        Err(error) => log::error!("Failed to get media files stored in anki: {error}"),
+    }
+    let mut sync_state = exit_on_err(initialize_notes(), "Failed to initialize notes");
+
+    if env::args().skip(1).any(|arg| &arg == "--clean-media") {
+        if let Err(error) = media::clean_media(&sync_state) {
+            log::error!("Failed to clean media: {error}");
+        }
+        if let Err(error) = media::save_media_manifest() {
+            log::error!("Failed to save media manifest: {error}")
+        }
+        return;
+    }
 
     let track_seen = env::args().skip(1).any(|arg| &arg == "--track-seen");
+    let fix_stale_ids = env::args().skip(1).any(|arg| &arg == "--fix-stale-ids");
     let mut file_cache = env::args()
         .skip(1)
         .any(|arg| &arg == "--no-cache")
         .not()
         .then(|| match FileCache::load() {
-            Ok(cache) => Some(cache),
+            Ok(mut cache) => {
+                // unchanged files have to be handled again, to move their notes to the new decks
+                let deck_mappings = Some(deck_mappings_hash());
+                if cache.deck_mappings != deck_mappings {
+                    cache.hashes.clear();
+                    cache.deck_mappings = deck_mappings;
+                }
+                Some(cache)
+            }
             Err(error) => {
                 log::error!("Failed to load file cache, continuing without it: {error}");
                 None
@@ -158,14 +368,31 @@ fn main() {
         })
         .flatten();
 
+    let mut stats = SyncStats::default();
     exit_on_err(
-        traverse(PathBuf::from("."), &mut file_cache, track_seen),
+        traverse(
+            PathBuf::from("."),
+            &mut file_cache,
+            track_seen,
+            fix_stale_ids,
+            &mut sync_state,
+            &mut stats,
+        ),
         "Failed to traverse directory",
     );
 
+    report_duplicates(&sync_state);
+
+    // notes orphaned by a previous run whose cloze reappeared
+    match revive_orphans(&sync_state) {
+        Ok(0) => {}
+        Ok(count) => println!("Restored {count} previously orphaned notes"),
+        Err(error) => log::error!("Failed to restore previously orphaned notes: {error}"),
+    }
+
     // handle unseen notes if we have seen all present notes
     if (file_cache.is_none() || track_seen)
-        && let Err(err) = handle_unseen_notes()
+        && let Err(err) = handle_unseen_notes(&sync_state, orphan_policy)
     {
         log::error!("Failed to handle unseen notes: {err}");
     };
@@ -176,66 +403,58 @@ fn main() {
     {
         log::error!("Failed to save file cache: {error}")
     }
-}
 
-#[derive(Error, Debug)]
-enum FileCacheLoadError {
-    #[error("Failed to get path to file cache")]
-    GetPath,
-    #[error("Failed to open file cache: {0}")]
-    Open(#[from] std::io::Error),
-    #[error("Failed to deserialize file cache: {0}")]
-    Deserialize(#[from] serde_json::Error),
+    if let Err(error) = handle_md::save_math_cache() {
+        log::error!("Failed to save math cache: {error}")
+    }
+    if let Err(error) = media::save_media_manifest() {
+        log::error!("Failed to save media manifest: {error}")
+    }
+
+    println!("{stats}");
 }
 
-#[derive(Error, Debug)]
-enum FileCacheSaveError {
-    #[error("Failed to get path to file cache")]
-    GetPath,
-    #[error("Failed to create parent paths for the file cache: {0}")]
-    CreateParents(std::io::Error),
-    #[error("Failed to open file cache: {0}")]
-    Open(std::io::Error),
-    #[error("Failed to serialize file cache: {0}")]
-    Serialize(#[from] serde_json::Error),
+/// Prints the notes whose cloze was found in several places, with those places
+fn report_duplicates(sync_state: &SyncState) {
+    let duplicates = sync_state.duplicates();
+    if duplicates.is_empty() {
+        return;
+    }
+    match CONFIG.duplicate_clozes {
+        DuplicateClozes::Dedupe => println!(
+            "Identical clozes found in several places. Ones sharing a note are only updated from the first place:"
+        ),
+        DuplicateClozes::Separate => {
+            println!("Identical clozes found in several places, which were given separate notes:")
+        }
+    }
+    for (note, locations, notes) in duplicates {
+        if notes > 1 {
+            println!("- {} ({notes} notes)", note.summary());
+        } else {
+            println!("- {}", note.summary());
+        }
+        for location in locations {
+            println!("  {location}");
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Default)]
 struct FileCache {
     /// source_dir -> file -> hash
     hashes: HashMap<PathBuf, HashMap<PathBuf, Hash>>,
+    /// hash of the deck mappings of the config the files were handled with
+    #[serde(default)]
+    deck_mappings: Option<Hash>,
 }
-impl FileCache {
-    fn get_path() -> Option<PathBuf> {
-        Some(dirs::cache_dir()?.join("anksidian/file_cache.json"))
-    }
-    fn load() -> Result<Self, FileCacheLoadError> {
-        let path = Self::get_path().ok_or(FileCacheLoadError::GetPath)?;
-        if !path.exists() {
-            Ok(Self::default())
-        } else {
-            let file = File::open_buffered(&path).map_err(FileCacheLoadError::Open)?;
-            Ok(serde_json::from_reader(file)?)
-        }
-    }
-    fn save(&self) -> Result<(), FileCacheSaveError> {
-        let path = Self::get_path().ok_or(FileCacheSaveError::GetPath)?;
-        let parent = path.parent().expect("Path should have a parent");
-        if !parent.exists() {
-            fs::create_dir_all(parent).map_err(FileCacheSaveError::CreateParents)?;
-        }
-        let file = BufWriter::new(
-            OpenOptions::new()
-                .read(false)
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(path)
-                .map_err(FileCacheSaveError::Open)?,
-        );
-        serde_json::to_writer(file, self)?;
-        Ok(())
-    }
+impl Cache for FileCache {
+    const FILE_NAME: &'static str = "file_cache.json";
+}
+fn deck_mappings_hash() -> Hash {
+    let mappings = serde_json::to_string(&(&CONFIG.path_to_deck, &CONFIG.tag_to_deck))
+        .expect("Deck mappings should serialize to json");
+    blake3::hash(mappings.as_bytes())
 }
 fn hash_file(path: &Path) -> std::io::Result<Hash> {
     let mut file = File::open_buffered(path)?;
@@ -275,6 +494,9 @@ fn traverse(
     dir: PathBuf,
     file_cache: &mut Option<FileCache>,
     track_seen: bool,
+    fix_stale_ids: bool,
+    sync_state: &mut SyncState,
+    stats: &mut SyncStats,
 ) -> Result<(), TraverseError> {
     trace!("Recursing into dir {}", dir.display());
     for entry in dir
@@ -296,20 +518,32 @@ fn traverse(
                 .iter()
                 .any(|ignore_path| ignore_path.is_match(&canonicalized.to_string_lossy()))
         {
-            traverse(path, file_cache, track_seen)?;
+            traverse(
+                path,
+                file_cache,
+                track_seen,
+                fix_stale_ids,
+                sync_state,
+                stats,
+            )?;
         // markdown file
         } else if path.is_file()
             && let Some(extension) = path.extension()
             && extension == "md"
         {
-            let handle_and_wrap_md = |path: &Path| {
-                handle_md(path).map_err(|error| TraverseError::HandleMd {
-                    error,
-                    file: path.to_path_buf(),
-                })
-            };
+            let mut handle_and_wrap_md =
+                |path: &Path, sync_state: &mut SyncState| -> Result<(), TraverseError> {
+                    let recreate_stale = fix_stale_ids || CONFIG.recreate_stale_notes;
+                    *stats += handle_md(path, sync_state, recreate_stale).map_err(|error| {
+                        TraverseError::HandleMd {
+                            error,
+                            file: path.to_path_buf(),
+                        }
+                    })?;
+                    Ok(())
+                };
             match file_cache {
-                None => handle_and_wrap_md(&path)?,
+                None => handle_and_wrap_md(&path, sync_state)?,
                 Some(file_cache) => {
                     let file_hash = hash_file(&path).map_err(|error| TraverseError::Hash {
                         error,
@@ -320,20 +554,25 @@ fn traverse(
                         Some(deck_cache) => {
                             // file isn't in cache or hashes don't match
                             if deck_cache.get(&path) != Some(&file_hash) {
-                                handle_and_wrap_md(&path)?;
+                                handle_and_wrap_md(&path, sync_state)?;
                                 deck_cache.insert(path, file_hash);
-                            } else if track_seen {
-                                mark_notes_as_seen(&path).map_err(|error| {
-                                    TraverseError::MarkNotesAsSeen {
-                                        error,
-                                        file: path.clone(),
-                                    }
-                                })?;
+                            } else if track_seen || fix_stale_ids {
+                                let all_present =
+                                    mark_notes_as_seen(&path, sync_state).map_err(|error| {
+                                        TraverseError::MarkNotesAsSeen {
+                                            error,
+                                            file: path.clone(),
+                                        }
+                                    })?;
+                                // the file contains notes created by older versions or stale NoteIDs
+                                if !all_present {
+                                    handle_and_wrap_md(&path, sync_state)?;
+                                }
                             }
                         }
                         // current_dir is not in cache
                         None => {
-                            handle_and_wrap_md(&path)?;
+                            handle_and_wrap_md(&path, sync_state)?;
                             file_cache
                                 .hashes
                                 .insert(PWD.clone(), HashMap::from([(path, file_hash)]));
@@ -0,0 +1,153 @@
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, stdin},
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex, PoisonError},
+};
+use thiserror::Error;
+
+use crate::{
+    anki::{self, RequestError},
+    cache::{Cache, CacheSaveError},
+    hash_file,
+    sync_state::SyncState,
+};
+
+// Media files are stored in anki under names derived from their contents, so that
+// different files with the same name don't overwrite each other and identical files are stored once.
+
+/// canonicalized local path -> name in anki's media folder
+static MEDIA_NAMES: LazyLock<Mutex<HashMap<PathBuf, String>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The name in anki's media folder of the file at `path`, which is stored with `extension`
+pub fn media_name(path: &Path, extension: &str) -> io::Result<String> {
+    let canonicalized = path.canonicalize()?;
+    let mut names = MEDIA_NAMES.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(name) = names.get(&canonicalized) {
+        return Ok(name.clone());
+    }
+
+    let name = format!(
+        "anksidian-{}.{extension}",
+        hash_file(&canonicalized)?.to_hex()
+    );
+    names.insert(canonicalized, name.clone());
+    Ok(name)
+}
+
+/// The media files stored in anki, so unchanged files aren't uploaded again
+#[derive(Serialize, Deserialize, Default)]
+struct MediaManifest {
+    /// name in anki's media folder (which contains the hash of the contents) -> local path
+    files: HashMap<String, PathBuf>,
+}
+impl Cache for MediaManifest {
+    const FILE_NAME: &'static str = "media_manifest.json";
+}
+static MEDIA_MANIFEST: LazyLock<Mutex<MediaManifest>> = LazyLock::new(|| {
+    Mutex::new(MediaManifest::load().unwrap_or_else(|error| {
+        error!("Failed to load media manifest, uploading all media: {error}");
+        MediaManifest::default()
+    }))
+});
+
+/// Whether the media file `filename` is already stored in anki
+pub fn is_stored(filename: &str) -> bool {
+    MEDIA_MANIFEST
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .files
+        .contains_key(filename)
+}
+pub fn record_stored(filename: String, path: PathBuf) {
+    MEDIA_MANIFEST
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .files
+        .insert(filename, path);
+}
+/// Forget about media files that are no longer in anki, given the names of the ones still there
+pub fn retain_stored(names: &HashSet<String>) {
+    MEDIA_MANIFEST
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .files
+        .retain(|filename, _| names.contains(filename));
+}
+pub fn save_media_manifest() -> Result<(), CacheSaveError> {
+    MEDIA_MANIFEST
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .save()
+}
+
+#[derive(Error, Debug)]
+pub enum CleanMediaError {
+    #[error("AnkiConnect request failed: {0}")]
+    Request(#[from] RequestError),
+    #[error("Reading from stdin failed: {0}")]
+    Stdin(#[from] io::Error),
+}
+/// Deletes the media files uploaded by anksidian that no note references anymore, after asking for confirmation.
+/// Files that weren't uploaded by anksidian are never touched.
+pub fn clean_media(sync_state: &SyncState) -> Result<(), CleanMediaError> {
+    let candidates: Vec<(String, PathBuf)> = MEDIA_MANIFEST
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .files
+        .iter()
+        // the names contain the hash of the contents, so they can't accidentally be part of other text
+        .filter(|(filename, _)| {
+            !sync_state
+                .notes()
+                .flat_map(|note| note.fields.values())
+                .any(|value| value.contains(filename.as_str()))
+        })
+        .map(|(filename, path)| (filename.clone(), path.clone()))
+        .collect();
+    // notes not managed by anksidian (e.g. copies, or notes of older versions) may reference the files too
+    let mut unreferenced = Vec::with_capacity(candidates.len());
+    for (filename, path) in candidates {
+        if !anki::is_media_referenced(&filename)? {
+            unreferenced.push((filename, path));
+        }
+    }
+    if unreferenced.is_empty() {
+        println!("No unreferenced media files");
+        return Ok(());
+    }
+    unreferenced.sort();
+
+    println!("Media files uploaded by anksidian, but not referenced by any note:");
+    for (filename, path) in &unreferenced {
+        println!("{filename} (from {})", path.to_string_lossy());
+    }
+    println!("Delete these {} files from Anki? (y/n)", unreferenced.len());
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        // treat end of input as no
+        if stdin().read_line(&mut buf)? == 0 {
+            return Ok(());
+        }
+        match buf.trim() {
+            "Y" | "y" | "Yes" | "yes" => break,
+            "N" | "n" | "No" | "no" => return Ok(()),
+            other => println!("unknown option '{other}'"),
+        }
+    }
+
+    for (filename, _) in &unreferenced {
+        anki::delete_media_file(filename)?;
+        MEDIA_MANIFEST
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .files
+            .remove(filename);
+    }
+    println!("Deleted {} media files", unreferenced.len());
+    Ok(())
+}
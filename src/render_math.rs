@@ -0,0 +1,147 @@
+use blake3::Hasher;
+use std::{
+    env::temp_dir,
+    fs::{self, create_dir_all},
+    io::{self, Write as _},
+    path::PathBuf,
+    process::{Command, ExitStatusError, Stdio},
+};
+use thiserror::Error;
+
+// Renders math to svg files locally, for clients without a working MathJax.
+// The files are named after a hash of their source, so unchanged math isn't rendered again.
+
+#[derive(Error, Debug)]
+pub enum RenderMathError {
+    #[error("Failed to create render directory {dir}: {error}")]
+    CreateDir { dir: PathBuf, error: io::Error },
+    #[error("Failed to write {file}: {error}")]
+    Write { file: PathBuf, error: io::Error },
+    #[error("Failed to spawn {program} process: {error}")]
+    Spawn {
+        program: &'static str,
+        error: io::Error,
+    },
+    #[error("Failed to write to {program} process stdin: {error}")]
+    StdinWrite {
+        program: &'static str,
+        error: io::Error,
+    },
+    #[error("Failed to wait for {program} process: {error}")]
+    Wait {
+        program: &'static str,
+        error: io::Error,
+    },
+    #[error("{program} failed: {error}")]
+    ErrExit {
+        program: &'static str,
+        error: ExitStatusError,
+    },
+}
+
+/// Render typst math (including its $ delimiters) to an svg file, returning its path
+pub fn typst_to_svg(math: &str, preamble: &str) -> Result<PathBuf, RenderMathError> {
+    let source = format!("#set page(width: auto, height: auto, margin: 1pt)\n{preamble}\n{math}");
+    let (_, svg) = output_paths("typst", &source)?;
+    if svg.exists() {
+        return Ok(svg);
+    }
+
+    run(
+        Command::new("typst")
+            .args(["c", "-", "-f", "svg"])
+            .arg(&svg),
+        "typst",
+        Some(&source),
+    )?;
+    Ok(svg)
+}
+
+/// Render latex math (including its \( \) or \[ \] delimiters) to an svg file, returning its path.
+/// Requires `latex` and `dvisvgm` to be installed.
+pub fn latex_to_svg(math: &str, preamble: &str) -> Result<PathBuf, RenderMathError> {
+    let source = format!(
+        "\\documentclass[preview, border=1pt]{{standalone}}\n\
+        \\usepackage{{amsmath}}\n\
+        \\usepackage{{amssymb}}\n\
+        {preamble}\n\
+        \\begin{{document}}\n\
+        {math}\n\
+        \\end{{document}}\n"
+    );
+    let (dir, svg) = output_paths("latex", &source)?;
+    if svg.exists() {
+        return Ok(svg);
+    }
+
+    let tex = svg.with_extension("tex");
+    fs::write(&tex, source).map_err(|error| RenderMathError::Write {
+        file: tex.clone(),
+        error,
+    })?;
+    run(
+        Command::new("latex")
+            .args(["-interaction=nonstopmode", "-halt-on-error"])
+            .arg("-output-directory")
+            .arg(&dir)
+            .arg(&tex),
+        "latex",
+        None,
+    )?;
+    run(
+        Command::new("dvisvgm")
+            .args(["--no-fonts", "--exact-bbox", "-o"])
+            .arg(&svg)
+            .arg(svg.with_extension("dvi")),
+        "dvisvgm",
+        None,
+    )?;
+    Ok(svg)
+}
+
+/// The directory to render in and the path of the svg to render to, named after a hash of `source`
+fn output_paths(kind: &str, source: &str) -> Result<(PathBuf, PathBuf), RenderMathError> {
+    let dir = temp_dir().join("anksidian/math");
+    create_dir_all(&dir).map_err(|error| RenderMathError::CreateDir {
+        dir: dir.clone(),
+        error,
+    })?;
+
+    let mut hasher = Hasher::new();
+    hasher.update(kind.as_bytes());
+    hasher.update(source.as_bytes());
+    let svg = dir.join(format!("anksidian-math-{}.svg", hasher.finalize().to_hex()));
+    Ok((dir, svg))
+}
+
+fn run(
+    command: &mut Command,
+    program: &'static str,
+    stdin: Option<&str>,
+) -> Result<(), RenderMathError> {
+    let mut child = command
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|error| RenderMathError::Spawn { program, error })?;
+
+    if let Some(stdin) = stdin {
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(stdin.as_bytes())
+            .map_err(|error| RenderMathError::StdinWrite { program, error })?;
+    }
+
+    child
+        .wait()
+        .map_err(|error| RenderMathError::Wait { program, error })?
+        .exit_ok()
+        .map_err(|error| RenderMathError::ErrExit { program, error })
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+// Fuzzy comparison of note texts, used to relink clozes that lost their NoteID comment
+// and were edited slightly, instead of adding them as new notes.
+
+/// How similar the note texts `a` and `b` are, from 0 (nothing in common) to 1 (equal after normalization).
+/// Their cloze answers and their text as a whole are weighed equally.
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let answers_a = cloze_answers(a).join("\n");
+    let answers_b = cloze_answers(b).join("\n");
+    (dice(&answers_a, &answers_b) + dice(&normalize(a), &normalize(b))) / 2.0
+}
+
+/// The normalized answers of the clozes (`{{c1::answer::hint}}`) in `text`
+fn cloze_answers(text: &str) -> Vec<String> {
+    let mut answers = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{c") {
+        rest = &rest[start + 3..];
+        let Some(contents) = rest
+            .trim_start_matches(|c: char| c.is_ascii_digit())
+            .strip_prefix("::")
+        else {
+            continue;
+        };
+        // braces of e.g. latex inside the cloze
+        let mut depth = 0usize;
+        let mut end = contents.len();
+        let mut hint_start = None;
+        for (index, char) in contents.char_indices() {
+            match char {
+                '{' => depth += 1,
+                '}' if depth > 0 => depth -= 1,
+                '}' if contents[index..].starts_with("}}") => {
+                    end = index;
+                    break;
+                }
+                ':' if depth == 0
+                    && hint_start.is_none()
+                    && contents[index..].starts_with("::") =>
+                {
+                    hint_start = Some(index);
+                }
+                _ => {}
+            }
+        }
+        answers.push(normalize(&contents[..hint_start.unwrap_or(end)]));
+        rest = &contents[end..];
+    }
+    answers
+}
+
+/// Lowercase, without html tags and with whitespace collapsed
+fn normalize(text: &str) -> String {
+    let mut normalized = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for char in text.chars() {
+        match char {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                normalized.push(' ');
+            }
+            _ if in_tag => {}
+            _ => normalized.extend(char.to_lowercase()),
+        }
+    }
+    normalized.split_whitespace().intersperse(" ").collect()
+}
+
+/// Sørensen–Dice coefficient of the character bigrams of `a` and `b`
+fn dice(a: &str, b: &str) -> f64 {
+    fn bigrams(text: &str) -> HashMap<(char, char), usize> {
+        let mut bigrams = HashMap::new();
+        let chars: Vec<char> = text.chars().collect();
+        for pair in chars.windows(2) {
+            *bigrams.entry((pair[0], pair[1])).or_insert(0) += 1;
+        }
+        bigrams
+    }
+
+    if a == b {
+        return 1.0;
+    }
+    let (bigrams_a, bigrams_b) = (bigrams(a), bigrams(b));
+    let total: usize = bigrams_a.values().chain(bigrams_b.values()).sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let shared: usize = bigrams_a
+        .iter()
+        .map(|(bigram, count)| bigrams_b.get(bigram).map_or(0, |other| *count.min(other)))
+        .sum();
+    (2 * shared) as f64 / total as f64
+}
+
+#[test]
+fn test_similarity() {
+    assert_eq!(
+        cloze_answers(r"The {{c1::mitochondria::organelle}} is the {{c2::\frac{power}{house}}}"),
+        ["mitochondria", r"\frac{power}{house}"]
+    );
+    let original = "The {{c1::mitochondria}} is the powerhouse of the cell<br>biology > cells";
+    assert_eq!(similarity(original, original), 1.0);
+    assert!(
+        similarity(
+            original,
+            "The {{c1::Mitochondria}} is the powerhouse of the <b>cell</b><br>biology > organelles"
+        ) > 0.8
+    );
+    assert!(
+        similarity(
+            original,
+            "The {{c1::nucleus}} contains the DNA<br>biology > cells"
+        ) < 0.6
+    );
+}
@@ -0,0 +1,173 @@
+use blake3::Hash;
+use std::collections::HashMap;
+
+use crate::{
+    anki::{CardId, NoteId, UpdateNote},
+    handle_md::Location,
+    similarity::similarity,
+};
+
+// The managed notes present in anki, indexed by id and text field,
+// so that looking up the note of a cloze doesn't need to scan all notes.
+
+struct Entry {
+    note: UpdateNote,
+    cards: Vec<CardId>,
+    /// where the note was seen in files during this run
+    seen_at: Vec<Location>,
+}
+
+#[derive(Default)]
+pub struct SyncState {
+    /// in the order anki returned them
+    entries: Vec<Entry>,
+    by_id: HashMap<NoteId, usize>,
+    /// hash of the Text field -> indices of notes with that text
+    by_text: HashMap<Hash, Vec<usize>>,
+}
+impl SyncState {
+    pub fn new(notes: Vec<(UpdateNote, Vec<CardId>)>) -> Self {
+        let mut state = Self::default();
+        for (note, cards) in notes {
+            state.insert(note, cards);
+        }
+        state
+    }
+
+    /// Add a note that wasn't found when initializing, unless it is already present
+    pub fn insert(&mut self, note: UpdateNote, cards: Vec<CardId>) {
+        if self.by_id.contains_key(&note.id) {
+            return;
+        }
+        let index = self.entries.len();
+        self.by_id.insert(note.id, index);
+        self.by_text
+            .entry(blake3::hash(note.text().as_bytes()))
+            .or_default()
+            .push(index);
+        self.entries.push(Entry {
+            note,
+            cards,
+            seen_at: Vec::new(),
+        });
+    }
+
+    pub fn contains(&self, id: NoteId) -> bool {
+        self.by_id.contains_key(&id)
+    }
+
+    /// Find the note with `id`, or otherwise a note with the same `text`, preferring ones not seen yet
+    pub fn find(&self, id: Option<NoteId>, text: &str) -> Option<&UpdateNote> {
+        let index = id.and_then(|id| self.by_id.get(&id)).or_else(|| {
+            // guard against hash collisions
+            let mut same_text = self
+                .by_text
+                .get(&blake3::hash(text.as_bytes()))?
+                .iter()
+                .filter(|&&index| self.entries[index].note.text() == text);
+            let first = same_text.clone().next();
+            same_text
+                .find(|&&index| self.entries[index].seen_at.is_empty())
+                .or(first)
+        })?;
+        Some(&self.entries[*index].note)
+    }
+
+    /// Find the unseen note most similar to `text`, with a similarity of at least `threshold`,
+    /// among the notes accepted by `candidate`
+    pub fn find_similar(
+        &self,
+        text: &str,
+        threshold: f64,
+        candidate: impl Fn(&UpdateNote) -> bool,
+    ) -> Option<&UpdateNote> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.seen_at.is_empty() && candidate(&entry.note))
+            .map(|entry| (entry, similarity(text, entry.note.text())))
+            .filter(|(_, similarity)| *similarity >= threshold)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(entry, _)| &entry.note)
+    }
+
+    /// The note with `id`, if it is present
+    pub fn get(&self, id: NoteId) -> Option<&UpdateNote> {
+        self.by_id.get(&id).map(|&index| &self.entries[index].note)
+    }
+
+    /// Whether the note with `id` was already seen during this run
+    pub fn is_seen(&self, id: NoteId) -> bool {
+        self.by_id
+            .get(&id)
+            .is_some_and(|&index| !self.entries[index].seen_at.is_empty())
+    }
+
+    /// Mark the note with `id` as seen at `location`, if it is present
+    pub fn mark_seen(&mut self, id: NoteId, location: Location) {
+        if let Some(&index) = self.by_id.get(&id) {
+            self.entries[index].seen_at.push(location);
+        }
+    }
+
+    /// The cards of the note with `id`
+    pub fn cards(&self, id: NoteId) -> &[CardId] {
+        self.by_id
+            .get(&id)
+            .map_or(&[], |&index| &self.entries[index].cards)
+    }
+
+    /// All notes, in the order anki returned them
+    pub fn notes(&self) -> impl Iterator<Item = &UpdateNote> {
+        self.entries.iter().map(|entry| &entry.note)
+    }
+
+    /// Notes that were seen during this run
+    pub fn seen(&self) -> impl Iterator<Item = &UpdateNote> {
+        self.entries
+            .iter()
+            .filter(|entry| !entry.seen_at.is_empty())
+            .map(|entry| &entry.note)
+    }
+
+    /// Notes that weren't seen during this run
+    pub fn unseen(&self) -> impl Iterator<Item = &UpdateNote> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.seen_at.is_empty())
+            .map(|entry| &entry.note)
+    }
+
+    /// Identical clozes seen at more than one location during this run, whether they share a note or have
+    /// notes with the same text, with those locations and the number of notes they have
+    pub fn duplicates(&self) -> Vec<(&UpdateNote, Vec<&Location>, usize)> {
+        let mut duplicates = Vec::new();
+        for indices in self.by_text.values() {
+            let mut remaining: Vec<usize> = indices
+                .iter()
+                .copied()
+                .filter(|&index| !self.entries[index].seen_at.is_empty())
+                .collect();
+            // guard against hash collisions
+            while let Some(&first) = remaining.first() {
+                let text = self.entries[first].note.text();
+                let (same, other): (Vec<usize>, Vec<usize>) = remaining
+                    .iter()
+                    .partition(|&&index| self.entries[index].note.text() == text);
+                let locations: Vec<&Location> = same
+                    .iter()
+                    .flat_map(|&index| &self.entries[index].seen_at)
+                    .collect();
+                if locations.len() > 1 {
+                    duplicates.push((first, locations, same.len()));
+                }
+                remaining = other;
+            }
+        }
+        // in the order anki returned them
+        duplicates.sort_by_key(|(first, _, _)| *first);
+        duplicates
+            .into_iter()
+            .map(|(first, locations, notes)| (&self.entries[first].note, locations, notes))
+            .collect()
+    }
+}
@@ -0,0 +1,636 @@
+use std::{iter::Peekable, str::CharIndices};
+use thiserror::Error;
+
+// Converts the subset of typst math we use to latex, without having to spawn pandoc.
+// Anything not supported is reported as an error, so the caller can fall back to pandoc.
+
+#[derive(Error, Debug)]
+pub enum TypstMathError {
+    #[error("Unsupported typst math: {0}")]
+    Unsupported(String),
+    #[error("Unbalanced delimiters in typst math")]
+    Unbalanced,
+}
+
+/// Convert typst math (including its $ delimiters) to latex math (including \( \) or \[ \] delimiters)
+pub fn typst_to_latex(typst: &str) -> Result<String, TypstMathError> {
+    let inner = typst
+        .trim()
+        .strip_prefix('$')
+        .and_then(|typst| typst.strip_suffix('$'))
+        .ok_or_else(|| TypstMathError::Unsupported("missing $ delimiters".to_string()))?;
+    // typst uses display style if the math is surrounded by whitespace
+    let display = inner.starts_with(char::is_whitespace) && inner.ends_with(char::is_whitespace);
+
+    let mut parser = Parser {
+        tokens: tokenize(inner)?.into_iter().peekable(),
+    };
+    // alignment points and linebreaks are only supported at the top level, where they become an aligned environment
+    let mut lines = vec![vec![]];
+    loop {
+        let nodes = parser.parse_sequence(&[Token::Align, Token::Linebreak])?;
+        let line = lines.last_mut().expect("lines is never empty");
+        line.push(render_sequence(&nodes));
+        match parser.tokens.next() {
+            None => break,
+            Some(Token::Align) => {}
+            Some(Token::Linebreak) => lines.push(vec![]),
+            Some(_) => return Err(TypstMathError::Unbalanced),
+        }
+    }
+
+    let latex = match lines.as_slice() {
+        [line] if line.len() == 1 => line[0].clone(),
+        _ => {
+            let lines = lines
+                .iter()
+                .map(|line| line.join(" & "))
+                .collect::<Vec<_>>()
+                .join(" \\\\ ");
+            format!("\\begin{{aligned}} {lines} \\end{{aligned}}")
+        }
+    };
+    Ok(if display {
+        format!("\\[{latex}\\]")
+    } else {
+        format!("\\({latex}\\)")
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(String),
+    String(String),
+    /// an operator or other character, already converted to latex
+    Symbol(String),
+    Open(char),
+    Close(char),
+    Comma,
+    Semicolon,
+    Underscore,
+    Caret,
+    Slash,
+    /// an alignment point (&)
+    Align,
+    Linebreak,
+    /// a function call, which is an identifier directly followed by '('
+    Call(String),
+}
+
+fn tokenize(typst: &str) -> Result<Vec<Token>, TypstMathError> {
+    /// Multi-character shorthands, longest first
+    const SHORTHANDS: [(&str, &str); 15] = [
+        ("<==>", "\\Longleftrightarrow"),
+        ("<=>", "\\Leftrightarrow"),
+        ("==>", "\\Longrightarrow"),
+        ("...", "\\dots"),
+        ("->", "\\to"),
+        ("=>", "\\Rightarrow"),
+        ("<-", "\\leftarrow"),
+        ("<=", "\\leq"),
+        (">=", "\\geq"),
+        ("!=", "\\neq"),
+        ("<<", "\\ll"),
+        (">>", "\\gg"),
+        (":=", "\\coloneqq"),
+        ("||", "\\|"),
+        ("|->", "\\mapsto"),
+    ];
+
+    let mut tokens = Vec::new();
+    let mut chars: Peekable<CharIndices> = typst.char_indices().peekable();
+    'outer: while let Some((i, char)) = chars.next() {
+        let token = match char {
+            char if char.is_whitespace() => continue,
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => {
+                            if let Some((_, escaped)) = chars.next() {
+                                string.push(escaped)
+                            }
+                        }
+                        Some((_, char)) => string.push(char),
+                        None => return Err(TypstMathError::Unbalanced),
+                    }
+                }
+                Token::String(string)
+            }
+            '0'..='9' => {
+                let mut number = String::from(char);
+                while let Some(&(_, char)) = chars.peek()
+                    && (char.is_ascii_digit() || char == '.')
+                {
+                    number.push(char);
+                    chars.next();
+                }
+                Token::Number(number)
+            }
+            char if char.is_alphabetic() => {
+                let mut ident = String::from(char);
+                while let Some(&(_, char)) = chars.peek()
+                    && char.is_alphabetic()
+                {
+                    ident.push(char);
+                    chars.next();
+                }
+                // modifiers of multi-letter symbols (arrow.r.double)
+                if ident.chars().count() > 1 {
+                    while let Some(&(dot_index, '.')) = chars.peek()
+                        && typst[dot_index + 1..].starts_with(char::is_alphabetic)
+                    {
+                        chars.next();
+                        ident.push('.');
+                        while let Some(&(_, char)) = chars.peek()
+                            && char.is_alphabetic()
+                        {
+                            ident.push(char);
+                            chars.next();
+                        }
+                    }
+                }
+                match chars.peek() {
+                    Some((_, '(')) => {
+                        chars.next();
+                        Token::Call(ident)
+                    }
+                    _ => Token::Ident(ident),
+                }
+            }
+            '\\' => match chars.next() {
+                None => Token::Linebreak,
+                Some((_, char)) if char.is_whitespace() => Token::Linebreak,
+                Some((_, char @ ('{' | '}' | '#' | '$' | '%' | '&' | '_'))) => {
+                    Token::Symbol(format!("\\{char}"))
+                }
+                Some((_, char)) => Token::Symbol(char.to_string()),
+            },
+            '#' => return Err(TypstMathError::Unsupported(typst[i..].to_string())),
+            '(' | '[' | '{' => Token::Open(char),
+            ')' | ']' | '}' => Token::Close(char),
+            ',' => Token::Comma,
+            ';' => Token::Semicolon,
+            '_' => Token::Underscore,
+            '^' => Token::Caret,
+            '/' => Token::Slash,
+            '&' => Token::Align,
+            _ => {
+                for (shorthand, latex) in SHORTHANDS {
+                    if typst[i..].starts_with(shorthand) {
+                        for _ in 1..shorthand.chars().count() {
+                            chars.next();
+                        }
+                        tokens.push(Token::Symbol(latex.to_string()));
+                        continue 'outer;
+                    }
+                }
+                Token::Symbol(match char {
+                    '~' => "\\sim".to_string(),
+                    '%' => "\\%".to_string(),
+                    char => char.to_string(),
+                })
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Node {
+    /// already converted to latex
+    Atom(String),
+    Group {
+        open: char,
+        contents: Vec<Node>,
+        close: char,
+    },
+    Attach {
+        base: Box<Node>,
+        sub: Option<Box<Node>>,
+        sup: Option<Box<Node>>,
+    },
+    Frac(Box<Node>, Box<Node>),
+}
+impl Node {
+    /// Whether the node is tall enough for typst to scale surrounding delimiters
+    fn is_tall(&self) -> bool {
+        match self {
+            Node::Atom(latex) => latex.starts_with("\\begin"),
+            Node::Group { contents, .. } => contents.iter().any(Node::is_tall),
+            Node::Attach { base, .. } => base.is_tall(),
+            Node::Frac(..) => true,
+        }
+    }
+}
+
+struct Parser {
+    tokens: Peekable<std::vec::IntoIter<Token>>,
+}
+impl Parser {
+    /// Parse nodes until the end, a closing delimiter, or one of `terminators`
+    fn parse_sequence(&mut self, terminators: &[Token]) -> Result<Vec<Node>, TypstMathError> {
+        let mut nodes = Vec::new();
+        while let Some(token) = self.tokens.peek() {
+            if matches!(token, Token::Close(_)) || terminators.contains(token) {
+                break;
+            }
+            let mut node = self.parse_attach()?;
+            while self.tokens.next_if_eq(&Token::Slash).is_some() {
+                let denominator = self.parse_attach()?;
+                node = Node::Frac(
+                    Box::new(strip_parens(node)),
+                    Box::new(strip_parens(denominator)),
+                );
+            }
+            nodes.push(node);
+        }
+        Ok(nodes)
+    }
+
+    /// Parse an atom with its sub- and superscripts
+    fn parse_attach(&mut self) -> Result<Node, TypstMathError> {
+        let base = self.parse_atom()?;
+        let (mut sub, mut sup) = (None, None);
+        loop {
+            let slot = match self.tokens.peek() {
+                Some(Token::Underscore) => &mut sub,
+                Some(Token::Caret) => &mut sup,
+                _ => break,
+            };
+            self.tokens.next();
+            // x^-1
+            let attachment = if self
+                .tokens
+                .next_if_eq(&Token::Symbol("-".to_string()))
+                .is_some()
+            {
+                Node::Atom(format!("-{}", render(&self.parse_atom()?)))
+            } else {
+                strip_parens(self.parse_atom()?)
+            };
+            *slot = Some(Box::new(attachment));
+        }
+        Ok(if sub.is_none() && sup.is_none() {
+            base
+        } else {
+            Node::Attach {
+                base: Box::new(base),
+                sub,
+                sup,
+            }
+        })
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, TypstMathError> {
+        let Some(token) = self.tokens.next() else {
+            return Err(TypstMathError::Unsupported(
+                "missing operand at the end".to_string(),
+            ));
+        };
+        Ok(match token {
+            Token::Number(number) => Node::Atom(number),
+            Token::String(string) => Node::Atom(format!("\\text{{{string}}}")),
+            Token::Symbol(symbol) => Node::Atom(symbol),
+            Token::Ident(ident) => Node::Atom(symbol(&ident)?),
+            Token::Open(open) => {
+                let contents = self.parse_sequence(&[])?;
+                let Some(Token::Close(close)) = self.tokens.next() else {
+                    return Err(TypstMathError::Unbalanced);
+                };
+                Node::Group {
+                    open,
+                    contents,
+                    close,
+                }
+            }
+            Token::Call(name) => self.parse_call(&name)?,
+            // outside of call arguments, they are just punctuation
+            Token::Comma => Node::Atom(",".to_string()),
+            Token::Semicolon => Node::Atom(";".to_string()),
+            other => return Err(TypstMathError::Unsupported(format!("{other:?}"))),
+        })
+    }
+
+    /// Parse the arguments of a call (rows separated by ';', with cells separated by ',') and convert it
+    fn parse_call(&mut self, name: &str) -> Result<Node, TypstMathError> {
+        let mut rows = vec![vec![]];
+        loop {
+            let cell = self.parse_sequence(&[Token::Comma, Token::Semicolon])?;
+            rows.last_mut().expect("rows is never empty").push(cell);
+            match self.tokens.next() {
+                Some(Token::Comma) => {}
+                Some(Token::Semicolon) => rows.push(vec![]),
+                Some(Token::Close(')')) => break,
+                _ => return Err(TypstMathError::Unbalanced),
+            }
+        }
+        // trailing comma or semicolon
+        if let Some(row) = rows.last_mut()
+            && row.last().is_some_and(Vec::is_empty)
+        {
+            row.pop();
+        }
+        if rows.last().is_some_and(Vec::is_empty) {
+            rows.pop();
+        }
+        call(name, rows)
+    }
+}
+
+/// Convert a call with its arguments
+fn call(name: &str, mut rows: Vec<Vec<Vec<Node>>>) -> Result<Node, TypstMathError> {
+    let matrix = |rows: &[Vec<Vec<Node>>], environment: &str| {
+        let rows = rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| render_sequence(cell))
+                    .collect::<Vec<_>>()
+                    .join(" & ")
+            })
+            .collect::<Vec<_>>()
+            .join(" \\\\ ");
+        format!("\\begin{{{environment}}} {rows} \\end{{{environment}}}")
+    };
+
+    let args = match rows.as_slice() {
+        [row] => row.iter().map(|arg| render_sequence(arg)).collect(),
+        _ => Vec::new(),
+    };
+    let latex = match (name, args.as_slice()) {
+        ("mat", _) => matrix(&rows, "pmatrix"),
+        ("vec", _) => matrix(
+            &rows
+                .pop()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|cell| vec![cell])
+                .collect::<Vec<_>>(),
+            "pmatrix",
+        ),
+        ("cases", _) => matrix(
+            &rows
+                .pop()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|cell| vec![cell])
+                .collect::<Vec<_>>(),
+            "cases",
+        ),
+        ("frac", [numerator, denominator]) => format!("\\frac{{{numerator}}}{{{denominator}}}"),
+        ("binom", [n, k]) => format!("\\binom{{{n}}}{{{k}}}"),
+        ("sqrt", [radicand]) => format!("\\sqrt{{{radicand}}}"),
+        ("root", [index, radicand]) => format!("\\sqrt[{index}]{{{radicand}}}"),
+        ("abs", [arg]) => format!("\\left| {arg} \\right|"),
+        ("norm", [arg]) => format!("\\left\\| {arg} \\right\\|"),
+        ("floor", [arg]) => format!("\\left\\lfloor {arg} \\right\\rfloor"),
+        ("ceil", [arg]) => format!("\\left\\lceil {arg} \\right\\rceil"),
+        ("lr", [_]) => {
+            let mut cell = rows.pop().and_then(|mut row| row.pop()).unwrap_or_default();
+            match cell.as_mut_slice() {
+                [
+                    Node::Group {
+                        open,
+                        contents,
+                        close,
+                    },
+                ] => format!(
+                    "\\left{} {} \\right{}",
+                    delimiter(*open),
+                    render_sequence(contents),
+                    delimiter(*close)
+                ),
+                _ => return Err(TypstMathError::Unsupported(format!("{name}(...)"))),
+            }
+        }
+        ("op", [arg]) => format!("\\operatorname{{{}}}", strip_text(arg)),
+        (name, [arg]) => {
+            let command = match name {
+                "hat" => "\\hat",
+                "tilde" => "\\tilde",
+                "bar" | "macron" => "\\bar",
+                "overline" => "\\overline",
+                "underline" => "\\underline",
+                "dot" => "\\dot",
+                "dot.double" => "\\ddot",
+                "arrow" => "\\vec",
+                "overbrace" => "\\overbrace",
+                "underbrace" => "\\underbrace",
+                "upright" => "\\mathrm",
+                "bold" => "\\mathbf",
+                "italic" => "\\mathit",
+                "cal" => "\\mathcal",
+                "bb" => "\\mathbb",
+                "frak" => "\\mathfrak",
+                "sans" => "\\mathsf",
+                "mono" => "\\mathtt",
+                // symbols followed by parentheses, like sin(x)
+                _ => {
+                    return Ok(Node::Atom(format!(
+                        "{} \\left( {arg} \\right)",
+                        symbol(name)?
+                    )));
+                }
+            };
+            format!("{command}{{{arg}}}")
+        }
+        _ => return Err(TypstMathError::Unsupported(format!("{name}(...)"))),
+    };
+    Ok(Node::Atom(latex))
+}
+
+/// Convert a typst symbol to latex
+fn symbol(name: &str) -> Result<String, TypstMathError> {
+    const GREEK: [&str; 35] = [
+        "alpha", "beta", "gamma", "delta", "epsilon", "zeta", "eta", "theta", "iota", "kappa",
+        "lambda", "mu", "nu", "xi", "pi", "rho", "sigma", "tau", "upsilon", "phi", "chi", "psi",
+        "omega", "Gamma", "Delta", "Theta", "Lambda", "Xi", "Pi", "Sigma", "Upsilon", "Phi", "Psi",
+        "Omega", "varsigma",
+    ];
+    const OPERATORS: [&str; 26] = [
+        "sin", "cos", "tan", "cot", "sec", "csc", "arcsin", "arccos", "arctan", "sinh", "cosh",
+        "tanh", "ln", "log", "exp", "det", "min", "max", "sup", "inf", "gcd", "lim", "arg", "deg",
+        "dim", "ker",
+    ];
+
+    if name.chars().count() == 1 {
+        return Ok(name.to_string());
+    }
+    if GREEK.contains(&name) || OPERATORS.contains(&name) {
+        return Ok(format!("\\{name}"));
+    }
+    Ok(match name {
+        "epsilon.alt" => "\\varepsilon",
+        "phi.alt" => "\\varphi",
+        "theta.alt" => "\\vartheta",
+        "dot" | "dot.op" | "dot.c" => "\\cdot",
+        "times" => "\\times",
+        "div" => "\\div",
+        "plus.minus" => "\\pm",
+        "minus.plus" => "\\mp",
+        "infinity" | "oo" => "\\infty",
+        "partial" => "\\partial",
+        "nabla" => "\\nabla",
+        "dots" | "dots.h" => "\\dots",
+        "dots.c" => "\\cdots",
+        "dots.v" => "\\vdots",
+        "dots.down" => "\\ddots",
+        "in" => "\\in",
+        "in.not" => "\\notin",
+        "subset" => "\\subset",
+        "subset.eq" => "\\subseteq",
+        "supset" => "\\supset",
+        "supset.eq" => "\\supseteq",
+        "union" => "\\cup",
+        "sect" => "\\cap",
+        "without" => "\\setminus",
+        "emptyset" => "\\emptyset",
+        "forall" => "\\forall",
+        "exists" => "\\exists",
+        "not" => "\\neg",
+        "and" => "\\wedge",
+        "or" => "\\vee",
+        "approx" => "\\approx",
+        "prop" => "\\propto",
+        "equiv" => "\\equiv",
+        "eq" => "=",
+        "eq.not" => "\\neq",
+        "lt" => "<",
+        "gt" => ">",
+        "lt.eq" => "\\leq",
+        "gt.eq" => "\\geq",
+        "arrow.r" => "\\rightarrow",
+        "arrow.l" => "\\leftarrow",
+        "arrow.l.r" => "\\leftrightarrow",
+        "arrow.r.double" => "\\Rightarrow",
+        "arrow.l.double" => "\\Leftarrow",
+        "arrow.l.r.double" => "\\Leftrightarrow",
+        "arrow.r.bar" => "\\mapsto",
+        "compose" => "\\circ",
+        "degree" => "^\\circ",
+        "angle" => "\\angle",
+        "perp" => "\\perp",
+        "parallel" => "\\parallel",
+        "ell" => "\\ell",
+        "planck.reduce" => "\\hbar",
+        "sum" => "\\sum",
+        "product" => "\\prod",
+        "integral" => "\\int",
+        "integral.double" => "\\iint",
+        "integral.triple" => "\\iiint",
+        "integral.cont" => "\\oint",
+        "RR" => "\\mathbb{R}",
+        "NN" => "\\mathbb{N}",
+        "ZZ" => "\\mathbb{Z}",
+        "QQ" => "\\mathbb{Q}",
+        "CC" => "\\mathbb{C}",
+        "quad" => "\\quad",
+        "wide" => "\\qquad",
+        "space" => "\\ ",
+        "mod" => "\\bmod",
+        _ => return Err(TypstMathError::Unsupported(name.to_string())),
+    }
+    .to_string())
+}
+
+/// Remove the parentheses of a group, as typst does for fractions and attachments
+fn strip_parens(node: Node) -> Node {
+    match node {
+        Node::Group {
+            open: '(',
+            contents,
+            close: ')',
+        } => Node::Atom(render_sequence(&contents)),
+        other => other,
+    }
+}
+
+/// Remove the \text{} that strings are converted to
+fn strip_text(latex: &str) -> &str {
+    latex
+        .strip_prefix("\\text{")
+        .and_then(|latex| latex.strip_suffix('}'))
+        .unwrap_or(latex)
+}
+
+fn delimiter(char: char) -> String {
+    match char {
+        '{' | '}' => format!("\\{char}"),
+        char => char.to_string(),
+    }
+}
+
+fn render_sequence(nodes: &[Node]) -> String {
+    nodes.iter().map(render).collect::<Vec<_>>().join(" ")
+}
+
+fn render(node: &Node) -> String {
+    match node {
+        Node::Atom(latex) => latex.clone(),
+        Node::Group {
+            open,
+            contents,
+            close,
+        } => {
+            let contents = render_sequence(contents);
+            if node.is_tall() {
+                format!(
+                    "\\left{} {contents} \\right{}",
+                    delimiter(*open),
+                    delimiter(*close)
+                )
+            } else {
+                format!("{}{contents}{}", delimiter(*open), delimiter(*close))
+            }
+        }
+        Node::Attach { base, sub, sup } => {
+            let mut latex = match **base {
+                Node::Atom(ref atom) => atom.clone(),
+                ref other => format!("{{{}}}", render(other)),
+            };
+            if let Some(sub) = sub {
+                latex.push_str(&format!("_{{{}}}", render(sub)));
+            }
+            if let Some(sup) = sup {
+                latex.push_str(&format!("^{{{}}}", render(sup)));
+            }
+            latex
+        }
+        Node::Frac(numerator, denominator) => {
+            format!("\\frac{{{}}}{{{}}}", render(numerator), render(denominator))
+        }
+    }
+}
+
+#[test]
+fn test_typst_to_latex() {
+    assert_eq!(
+        typst_to_latex("$ Delta V/L = V/L dot gamma/alpha dot Delta T $").ok(),
+        Some("\\[\\Delta \\frac{V}{L} = \\frac{V}{L} \\cdot \\frac{\\gamma}{\\alpha} \\cdot \\Delta T\\]".to_string())
+    );
+    assert_eq!(
+        typst_to_latex("$x_(i+1)^2 + (a+b)/c$").ok(),
+        Some("\\(x_{i + 1}^{2} + \\frac{a + b}{c}\\)".to_string())
+    );
+    assert_eq!(
+        typst_to_latex("$mat(1, 2; 3, 4) lr((a/b)) frac(1, 2)$").ok(),
+        Some(
+            "\\(\\begin{pmatrix} 1 & 2 \\\\ 3 & 4 \\end{pmatrix} \\left( \\frac{a}{b} \\right) \\frac{1}{2}\\)"
+                .to_string()
+        )
+    );
+    assert_eq!(
+        typst_to_latex("$ a &= b \\ c &= d $").ok(),
+        Some("\\[\\begin{aligned} a & = b \\\\ c & = d \\end{aligned}\\]".to_string())
+    );
+    assert_eq!(
+        typst_to_latex("$x, y in RR; (a, b)$").ok(),
+        Some("\\(x , y \\in \\mathbb{R} ; (a , b)\\)".to_string())
+    );
+    assert!(typst_to_latex("$abs(a & b)$").is_err());
+    assert!(typst_to_latex("$#calc.pow(2, 3)$").is_err());
+}